@@ -12,10 +12,89 @@ use bevy::reflect::Reflect;
 //   Willow
 // }
 
+/// How the v-coordinate of the bark texture is calculated along a branch
+#[derive(Reflect, Clone, Copy, Debug, PartialEq)]
+pub enum BarkUvMode {
+  /// v alternates between 0 and 1 every section (the texture is mirrored from section to section)
+  Alternating,
+  /// v accumulates along the branch by the world distance divided by the circumference (matching the texel density along u)
+  Continuous,
+}
+
+/// Shape of the rings (cross-sections) of a branch
+#[derive(Reflect, Clone, Copy, Debug, PartialEq)]
+pub enum CrossSection {
+  /// a perfect circle
+  Circle,
+  /// an ellipse with the given ratio of its minor to its major axis (0..1; the area stays roughly the same as the circle)
+  Ellipse(f32),
+  /// lobed/fluted outline (i.e. buttress trees); depth relative to the radius (0..0.9)
+  Lobed { lobes: u8, depth: f32 },
+  /// irregular outline perturbed by seeded noise; amount relative to the radius (0..0.9), frequency = amount of bumps around the ring
+  Noise { amount: f32, frequency: u8 },
+}
+
+/// How branches bend down under their own weight (see `GravityParams`)
+#[derive(Reflect, Clone, Copy, Debug, PartialEq)]
+pub enum GravityMode {
+  /// no gravity (only BranchForce)
+  None,
+  /// branches sag proportional to their load (thin long branches sag, thick ones don't)
+  Sag,
+  /// like Sag, but the tips of the branches hang down vertically (i.e. willows)
+  Weeping,
+}
+
+/// Closes the open end of a branch (i.e. the top of deciduous trunks, which keep their radius instead of ending in a tip)
+#[derive(Reflect, Clone, Copy, Debug, PartialEq)]
+pub enum EndCap {
+  /// the end stays open
+  None,
+  /// a flat disc
+  Flat,
+  /// a rounded dome
+  Dome,
+  /// a broken/jagged end (like a snapped off branch)
+  Jagged,
+}
+
+/// How child branches are connected to their parent branch
+#[derive(Reflect, Clone, Copy, Debug, PartialEq)]
+pub enum BranchJointMode {
+  /// child branches are separate tubes starting inside the parent
+  Intersect,
+  /// a flared collar which is welded onto the surface of the parent (looks grown rather than assembled)
+  Collar,
+}
+
 #[derive(Reflect, Clone, Copy, Debug, PartialEq)]
 pub enum LeafBillboard {
   Single,
   Double,
+  /// conifer needle clusters: thin crossed strips
+  Needles,
+  /// conifer foliage: crossed cards wrapped around the last-level twigs (one brush per twig; the leaf count is ignored)
+  BottleBrush,
+  /// fan shaped scale-leaf cards (i.e. cedars and cypresses)
+  ScaleFan,
+  /// single quads which are rotated towards the camera by a vertex shader (see `LeafBillboardMaterial`)
+  /// 
+  /// the TreeProceduralGenerationPlugin switches the leaves to a LeafBillboardMaterial (based on the leaf StandardMaterial) automatically
+  Billboard,
+}
+
+/// How the normals of the leaves are calculated
+/// (spherical normals let the canopy shade as a soft volume instead of many flat cards)
+#[derive(Reflect, Clone, Copy, Debug, PartialEq)]
+pub enum LeafNormalMode {
+  /// normal of the leaf card (orthogonal to its surface)
+  Flat,
+  /// pointing away from the center of the crown (center of the bounding box of all leaves)
+  SphericalCrown,
+  /// pointing away from the point where the leaf is attached to its branch (tilted towards the face of the leaf card)
+  SphericalBranch,
+  /// blend between Flat (0.0) and SphericalCrown (1.0) normals
+  Blended(f32),
 }
 
 // #[derive(Reflect, Clone, Copy, Debug, PartialEq)]
@@ -26,8 +105,25 @@ pub enum LeafBillboard {
 //   Oak,
 // }
 
+/// How the branches of a tree are generated
+#[derive(Reflect, Clone, Copy, Debug, PartialEq)]
+pub enum GenerationAlgorithm {
+  /// parametric recursion: each branch spawns child branches along its length (see `BranchParams`)
+  Recursive,
+  /// branches grow towards attraction points inside of a crown volume (see `SpaceColonizationParams`);
+  /// gives natural, evenly filled crowns
+  SpaceColonization,
+  /// a turtle draws the string derived from an axiom and production rules (see `LSystemParams`);
+  /// for plants which do not fit the structure of BranchParams (i.e. vines, ferns or stylized trees)
+  LSystem,
+}
+
 #[derive(Reflect, Clone, Copy, Debug, PartialEq)]
 pub enum TreeType {
   Deciduous,
   Evergreen,
+  /// an unbranched, curved trunk with ring-segmented bark uvs and a crown of fronds (see `PalmParams`)
+  Palm,
+  /// many thin stems spawning from (or just below) the ground without a distinct trunk (see `ShrubParams`)
+  Shrub,
 }
\ No newline at end of file
@@ -9,4 +9,40 @@ impl Display for IndicesOverflowError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Indices overflow in mesh generation: Please reduce amount of sections, segments or leaves or enable the u32_indices feature.")
     }
-}
\ No newline at end of file
+}
+
+/// The outline of a leaf (see `LeafShape::Outline`) has no area or intersects itself
+#[derive(Debug)]
+pub struct LeafShapeError;
+
+impl Error for LeafShapeError {}
+
+impl Display for LeafShapeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid leaf outline: the points of LeafShape::Outline have to form a simple polygon with an area (without self-intersections).")
+    }
+}
+
+/// The trunk of a space colonization tree (see `SpaceColonizationParams`) needs too many steps to reach the crown
+#[derive(Debug)]
+pub struct SpaceColonizationError;
+
+impl Error for SpaceColonizationError {}
+
+impl Display for SpaceColonizationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid space colonization: the trunk does not reach the crown. Please reduce the crown height or increase the step length.")
+    }
+}
+
+/// An axiom or rule of an L-system (see `LSystemParams`) could not be parsed or the derived string got too long
+#[derive(Debug)]
+pub struct LSystemError(pub String);
+
+impl Error for LSystemError {}
+
+impl Display for LSystemError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid L-system: {}", self.0)
+    }
+}
@@ -3,11 +3,17 @@ pub mod settings;
 pub mod errors;
 
 pub mod meshgen;
+pub mod materials;
+pub mod obstacles;
+mod lsystem;
+mod noise;
+mod occlusion;
+mod space_colonization;
 
 use bevy::{ecs::{component::HookContext, world::DeferredWorld}, prelude::*};
 use fastrand::Rng;
 
-use crate::{meshgen::generate_tree_meshes, settings::TreeMeshSettings};
+use crate::{enums::LeafBillboard, materials::{build_materials, LeafBillboardExtension, LeafBillboardMaterial}, meshgen::{apply_season, generate_tree_meshes_with_context, morph_growth, TreeGenerationContext}, obstacles::Obstacle, settings::{SeasonParams, TreeMeshSettings}};
 
 
 pub struct TreeProceduralGenerationPlugin;
@@ -19,10 +25,17 @@ impl Plugin for TreeProceduralGenerationPlugin {
         app.init_resource::<TreeDefaultMaterials>();
         app.register_type::<TreeDefaultMaterials>();
         app.register_type::<Tree>();
+        app.register_type::<TreeGrowth>();
+        app.register_type::<TreeGrowthAnimation>();
+        app.register_type::<TreeSeason>();
         app.register_type::<Leaves>();
+        build_materials(app);
 
         app.add_systems(PostUpdate, update_all_tree_meshes_with_global_settings.run_if(resource_changed::<TreeMeshSettings>));
         app.add_systems(PostUpdate, update_all_tree_meshes_with_local_settings);
+        app.add_systems(PostUpdate, update_leaf_billboard_materials.after(update_all_tree_meshes_with_local_settings));
+        app.add_systems(PostUpdate, sync_leaf_billboard_materials);
+        app.add_systems(PostUpdate, update_modified_tree_meshes.after(update_all_tree_meshes_with_local_settings).after(update_all_tree_meshes_with_global_settings));
     }
 }
 
@@ -44,6 +57,118 @@ pub struct Tree {
 }
 
 
+/// Obstacles the tree grows around (i.e. walls, rocks or neighbouring trees); in the local space of the tree entity
+/// 
+/// Added to an entity with a `Tree` component. Changing the obstacles regenerates the tree.
+#[derive(Component, Clone, Debug, Default)]
+pub struct TreeObstacles(pub Vec<Obstacle>);
+
+/// Age of the tree; changing the age regenerates the tree (i.e. to let saplings grow over time)
+/// 
+/// Added to an entity with a `Tree` component. Trunk and branches lengthen and thicken, new levels and leaves appear.
+/// The same seed and settings grow the same tree (see `TreeGenerationContext::age`).
+#[derive(Component, Reflect, Clone, Debug)]
+pub struct TreeGrowth {
+    /// current age (in any unit, i.e. years)
+    pub age: f32,
+    /// age at which the tree is fully grown (1.0 = the age is given as 0..1)
+    pub maturity_age: f32,
+}
+
+impl Default for TreeGrowth {
+    fn default() -> Self {
+        Self {
+            age: 1.0,
+            maturity_age: 1.0,
+        }
+    }
+}
+
+impl TreeGrowth {
+    /// age relative to the maturity age (0..1)
+    pub fn normalized_age(&self) -> f32 {
+        (self.age / self.maturity_age.max(f32::EPSILON)).clamp(0.0, 1.0)
+    }
+}
+
+/// Progress of the growth animation of the tree (0 = nothing grown yet, 1 = grown to the age of the tree)
+/// 
+/// Added to an entity with a `Tree` component. The meshes are generated once with the growth attributes (see `TreeGenerationContext::growth_animation`);
+/// changing the progress only morphs them (see `morph_growth()`) instead of regenerating the tree.
+#[derive(Component, Reflect, Clone, Debug, Default)]
+pub struct TreeGrowthAnimation {
+    pub progress: f32,
+}
+
+/// Time of the year of the tree: the leaves change their color and fall in autumn (see `SeasonParams`)
+/// 
+/// Added to an entity with a `Tree` component. The leaves mesh is generated once with the season attributes (see `TreeGenerationContext::seasons`);
+/// changing the season only recolors it (see `apply_season()`) instead of regenerating the tree.
+#[derive(Component, Reflect, Clone, Copy, Debug, Default, PartialEq)]
+pub enum TreeSeason {
+    Spring,
+    #[default]
+    Summer,
+    Autumn,
+    Winter,
+    /// time of the year: 0.0 = spring, 0.25 = summer, 0.5 = autumn, 0.75 = winter (wraps around)
+    Continuous(f32),
+}
+
+impl TreeSeason {
+    /// time of the year (0..1; see TreeSeason::Continuous)
+    pub fn time(&self) -> f32 {
+        match self {
+            TreeSeason::Spring => 0.0,
+            TreeSeason::Summer => 0.25,
+            TreeSeason::Autumn => 0.5,
+            TreeSeason::Winter => 0.75,
+            TreeSeason::Continuous(time) => time.rem_euclid(1.0),
+        }
+    }
+}
+
+/// the generated (unmodified) meshes of a tree with a growth animation or season
+#[derive(Component)]
+struct GeneratedTreeMeshes {
+    branches: Handle<Mesh>,
+    leaves: Handle<Mesh>,
+}
+
+fn generation_context(obstacles: Option<&TreeObstacles>, growth: Option<&TreeGrowth>, animation: Option<&TreeGrowthAnimation>, season: Option<&TreeSeason>) -> TreeGenerationContext {
+    TreeGenerationContext {
+        obstacles: obstacles.map(|obstacles| obstacles.0.clone()).unwrap_or_default(),
+        age: growth.map_or(1.0, TreeGrowth::normalized_age),
+        growth_animation: animation.is_some(),
+        seasons: season.is_some(),
+    }
+}
+
+/// the displayed meshes of a tree with a growth animation or season
+fn modify_tree_meshes(branches_mesh: &Mesh, leaves_mesh: &Mesh, animation: Option<&TreeGrowthAnimation>, season: Option<(&TreeSeason, &SeasonParams)>) -> (Mesh, Mesh) {
+    let (branches_mesh, leaves_mesh) = match animation {
+        Some(animation) => (morph_growth(branches_mesh, animation.progress), morph_growth(leaves_mesh, animation.progress)),
+        None => (branches_mesh.clone(), leaves_mesh.clone()),
+    };
+    match season {
+        Some((season, season_params)) => (branches_mesh, apply_season(&leaves_mesh, season.time(), season_params)),
+        None => (branches_mesh, leaves_mesh),
+    }
+}
+
+/// add the generated meshes; trees with a growth animation or season show modified copies of them
+fn add_tree_meshes(meshes: &mut Assets<Mesh>, branches_mesh: Mesh, leaves_mesh: Mesh, animation: Option<&TreeGrowthAnimation>, season: Option<(&TreeSeason, &SeasonParams)>) -> (Mesh3d, Mesh3d, Option<GeneratedTreeMeshes>) {
+    if animation.is_none() && season.is_none() {
+        return (Mesh3d(meshes.add(branches_mesh)), Mesh3d(meshes.add(leaves_mesh)), None);
+    }
+    let (displayed_branches, displayed_leaves) = modify_tree_meshes(&branches_mesh, &leaves_mesh, animation, season);
+    (
+        Mesh3d(meshes.add(displayed_branches)),
+        Mesh3d(meshes.add(displayed_leaves)),
+        Some(GeneratedTreeMeshes { branches: meshes.add(branches_mesh), leaves: meshes.add(leaves_mesh) }),
+    )
+}
+
 #[derive(Resource, Reflect)]
 struct TreeDefaultMaterials {
     /// defaults to Color::WHITE
@@ -66,6 +191,10 @@ impl FromWorld for TreeDefaultMaterials {
 #[derive(Component, Reflect)]
 struct Leaves(Entity);
 
+/// the leaf StandardMaterial a LeafBillboardMaterial was created from (only present while the leaves are rendered as billboards)
+#[derive(Component)]
+struct LeafBillboardSource(MeshMaterial3d<StandardMaterial>);
+
 fn new_tree_component_added(mut world: DeferredWorld, context: HookContext) {
     let tree_entity = context.entity;
 
@@ -78,16 +207,18 @@ fn new_tree_component_added(mut world: DeferredWorld, context: HookContext) {
       }  
     ).unwrap();
 
+    let animation = world.entity(tree_entity).get::<TreeGrowthAnimation>().cloned();
+    let season = world.entity(tree_entity).get::<TreeSeason>().copied();
+    let context = generation_context(world.entity(tree_entity).get::<TreeObstacles>(), world.entity(tree_entity).get::<TreeGrowth>(), animation.as_ref(), season.as_ref());
     let mut rng: Rng = Rng::with_seed(tree.seed);
 
-    match generate_tree_meshes(&tree_mesh_settings, &mut rng) {
+    match generate_tree_meshes_with_context(&tree_mesh_settings, &context, &mut rng) {
         Ok((branches_mesh, leaves_mesh)) => {
             // retrieve AssetServer
             let mut meshes = world.get_resource_mut::<Assets<Mesh>>().unwrap();
 
             // meshes
-            let branches_mesh = Mesh3d(meshes.add(branches_mesh));
-            let leaves_mesh = Mesh3d(meshes.add(leaves_mesh));
+            let (branches_mesh, leaves_mesh, generated_meshes) = add_tree_meshes(&mut meshes, branches_mesh, leaves_mesh, animation.as_ref(), season.as_ref().map(|season| (season, &tree_mesh_settings.leaves.season)));
 
             let default_materials = world.get_resource::<TreeDefaultMaterials>().unwrap();
             // bark material
@@ -111,15 +242,29 @@ fn new_tree_component_added(mut world: DeferredWorld, context: HookContext) {
                 branches_mesh,
                 branch_material
             )).add_child(leaves_id);
+            if let Some(generated_meshes) = generated_meshes {
+                tree_commands.insert(generated_meshes);
+            }
         },
         Err(err) => error!("Error during tree mesh generation: {}", err),
     }
 }
 
+/// trees whose settings, obstacles or age changed (or that got a growth animation or season)
+type ChangedTrees<'w, 's> = Query<'w, 's, Entity, Or<(Changed<Tree>, Changed<TreeObstacles>, Changed<TreeGrowth>, Added<TreeGrowthAnimation>, Added<TreeSeason>)>>;
 
+/// all trees with the optional inputs of their generation and their bark material
+type RegeneratedTrees<'w, 's> = Query<'w, 's,
+    (Entity, &'static Tree, Option<&'static TreeObstacles>, Option<&'static TreeGrowth>, Option<&'static TreeGrowthAnimation>, Option<&'static TreeSeason>, &'static MeshMaterial3d<StandardMaterial>, &'static Leaves)
+>;
+
+#[allow(clippy::too_many_arguments)]
 fn update_all_tree_meshes_with_local_settings(
-    trees: Query<(Entity, &Tree, &MeshMaterial3d<StandardMaterial>, &Leaves), Changed<Tree>>,
-    mesh_materials: Query<&MeshMaterial3d<StandardMaterial>>,
+    changed_trees: ChangedTrees,
+    trees: RegeneratedTrees,
+    mut removed_obstacles: RemovedComponents<TreeObstacles>,
+    mut removed_growths: RemovedComponents<TreeGrowth>,
+    mesh_materials: Query<(Option<&MeshMaterial3d<StandardMaterial>>, Option<&LeafBillboardSource>)>,
     mut meshes: ResMut<Assets<Mesh>>,
     global_tree_settings: Res<TreeMeshSettings>,
     default_materials: Res<TreeDefaultMaterials>,
@@ -128,21 +273,28 @@ fn update_all_tree_meshes_with_local_settings(
 {
     // For now we are regenerating the whole tree mesh each time 
     // TODO: Try to modify in place (or at least only branch/leaf levels or textures that need modification)
-    for (tree_entity, tree, current_bark_material, leaves_entity) in trees.iter() {
+    // trees without obstacles or growth (fully grown) anymore are regenerated like changed ones
+    let mut tree_entities: Vec<Entity> = changed_trees.iter().chain(removed_obstacles.read()).chain(removed_growths.read()).collect();
+    tree_entities.sort();
+    tree_entities.dedup();
+    for (tree_entity, tree, obstacles, growth, animation, season, current_bark_material, leaves_entity) in tree_entities.into_iter().filter_map(|tree_entity| trees.get(tree_entity).ok()) {
         let tree_settings: &TreeMeshSettings = match tree.tree_mesh_settings_override {
             Some(ref tree_settings) => tree_settings,
             None => global_tree_settings.as_ref(),
         };        
         
+        let context = generation_context(obstacles, growth, animation, season);
         let mut rng: Rng = Rng::with_seed(tree.seed);
 
-        match generate_tree_meshes(tree_settings, &mut rng) {
+        match generate_tree_meshes_with_context(tree_settings, &context, &mut rng) {
             Ok((branches_mesh, leaves_mesh)) => {
-                let branches_mesh = Mesh3d(meshes.add(branches_mesh));
-                let leaves_mesh = Mesh3d(meshes.add(leaves_mesh));
+                let (branches_mesh, leaves_mesh, generated_meshes) = add_tree_meshes(&mut meshes, branches_mesh, leaves_mesh, animation, season.map(|season| (season, &tree_settings.leaves.season)));
 
                 commands.entity(tree_entity).insert(branches_mesh);
                 commands.entity(leaves_entity.0).insert(leaves_mesh);        
+                if let Some(generated_meshes) = generated_meshes {
+                    commands.entity(tree_entity).insert(generated_meshes);
+                }
 
                 // check if the textures changed
                 match tree.bark_material_override { // what is the target state of the bark material
@@ -158,7 +310,8 @@ fn update_all_tree_meshes_with_local_settings(
                     },
                 }
 
-                if let Ok(current_leaf_material) = mesh_materials.get(leaves_entity.0) {
+                // billboard leaves are rendered with a material based on the leaf StandardMaterial (see update_leaf_billboard_materials)
+                if let Ok((Some(current_leaf_material), _) | (None, Some(LeafBillboardSource(current_leaf_material)))) = mesh_materials.get(leaves_entity.0) {
                     match tree.leaf_material_override { // what is the target state of the leaf material
                         Some(ref leaf_material_from_local_settings) => {
                             if !current_leaf_material.eq(leaf_material_from_local_settings) {
@@ -178,8 +331,11 @@ fn update_all_tree_meshes_with_local_settings(
     }
 }
 
+/// all trees with the optional inputs of their generation
+type AllTrees<'w, 's> = Query<'w, 's, (Entity, &'static Tree, Option<&'static TreeObstacles>, Option<&'static TreeGrowth>, Option<&'static TreeGrowthAnimation>, Option<&'static TreeSeason>, &'static Leaves)>;
+
 fn update_all_tree_meshes_with_global_settings(
-    trees: Query<(Entity, &Tree, &Leaves)>,
+    trees: AllTrees,
     tree_settings: Res<TreeMeshSettings>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut commands: Commands,
@@ -187,21 +343,181 @@ fn update_all_tree_meshes_with_global_settings(
     // For now we are regenerating the whole tree mesh each time 
     // TODO: Try to modify in place (or at least only branch/leaf levels or textures that need modification)
 
-    for (tree_entity, tree, leaves_entity) in trees.iter() {
+    for (tree_entity, tree, obstacles, growth, animation, season, leaves_entity) in trees.iter() {
         if tree.tree_mesh_settings_override.is_none() {
+            let context = generation_context(obstacles, growth, animation, season);
             let mut rng: Rng = Rng::with_seed(tree.seed);
 
-            match generate_tree_meshes(&tree_settings, &mut rng) {
+            match generate_tree_meshes_with_context(&tree_settings, &context, &mut rng) {
                 Ok((branches_mesh, leaves_mesh)) => {
-                    let branches_mesh = Mesh3d(meshes.add(branches_mesh));
-                    let leaves_mesh = Mesh3d(meshes.add(leaves_mesh));
+                    let (branches_mesh, leaves_mesh, generated_meshes) = add_tree_meshes(&mut meshes, branches_mesh, leaves_mesh, animation, season.map(|season| (season, &tree_settings.leaves.season)));
 
                     commands.entity(tree_entity).insert(branches_mesh);
                     commands.entity(leaves_entity.0).insert(leaves_mesh);
+                    if let Some(generated_meshes) = generated_meshes {
+                        commands.entity(tree_entity).insert(generated_meshes);
+                    }
                 },
                 Err(err) => error!("Error during tree mesh generation: {}", err),
             }
         }
     }
 
-}
\ No newline at end of file
+}
+
+/// all trees with their generated meshes and the modifications of them
+type ModifiedTrees<'w, 's> = Query<'w, 's, (&'static Tree, Option<&'static TreeGrowthAnimation>, Option<&'static TreeSeason>, &'static GeneratedTreeMeshes, &'static Mesh3d, &'static Leaves)>;
+
+/// trees with generated meshes whose growth animation or season changed
+type ChangedModifications<'w, 's> = Query<'w, 's, Entity, (With<GeneratedTreeMeshes>, Or<(Changed<TreeGrowthAnimation>, Changed<TreeSeason>)>)>;
+
+/// Modify the meshes of trees whose growth animation progressed or season changed (and restore the generated meshes if both were removed)
+#[allow(clippy::too_many_arguments)]
+fn update_modified_tree_meshes(
+    changed_trees: ChangedModifications,
+    trees: ModifiedTrees,
+    leaf_meshes: Query<&Mesh3d>,
+    mut removed_animations: RemovedComponents<TreeGrowthAnimation>,
+    mut removed_seasons: RemovedComponents<TreeSeason>,
+    global_tree_settings: Res<TreeMeshSettings>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut commands: Commands,
+) {
+    let removed: Vec<Entity> = removed_animations.read().chain(removed_seasons.read()).collect();
+    for tree_entity in changed_trees.iter().chain(removed) {
+        let Ok((tree, animation, season, generated_meshes, branches_mesh, leaves_entity)) = trees.get(tree_entity) else {
+            continue;
+        };
+        let Ok(leaves_mesh) = leaf_meshes.get(leaves_entity.0) else {
+            continue;
+        };
+
+        if animation.is_none() && season.is_none() {
+            commands.entity(tree_entity).remove::<GeneratedTreeMeshes>().insert(Mesh3d(generated_meshes.branches.clone()));
+            commands.entity(leaves_entity.0).insert(Mesh3d(generated_meshes.leaves.clone()));
+            continue;
+        }
+
+        let tree_settings: &TreeMeshSettings = match tree.tree_mesh_settings_override {
+            Some(ref tree_settings) => tree_settings,
+            None => global_tree_settings.as_ref(),
+        };
+        let (Some(generated_branches), Some(generated_leaves)) = (meshes.get(&generated_meshes.branches), meshes.get(&generated_meshes.leaves)) else {
+            continue;
+        };
+        let (branches, leaves) = modify_tree_meshes(generated_branches, generated_leaves, animation, season.map(|season| (season, &tree_settings.leaves.season)));
+        meshes.insert(&branches_mesh.0, branches);
+        meshes.insert(&leaves_mesh.0, leaves);
+    }
+}
+
+/// materials of the leaves: StandardMaterial, the one a LeafBillboardMaterial was created from and the LeafBillboardMaterial
+type LeafMaterials<'w, 's> = Query<'w, 's, (Option<&'static MeshMaterial3d<StandardMaterial>>, Option<&'static LeafBillboardSource>, Option<&'static MeshMaterial3d<LeafBillboardMaterial>>)>;
+
+/// trees with new settings or a new leaves entity
+type ChangedLeafTrees<'w, 's> = Query<'w, 's, Entity, Or<(Changed<Tree>, Changed<Leaves>)>>;
+
+/// leaves (children of their tree) with a new or replaced StandardMaterial
+type ChangedLeafMaterials<'w, 's> = Query<'w, 's, &'static ChildOf, Changed<MeshMaterial3d<StandardMaterial>>>;
+
+/// Leaves generated with LeafBillboard::Billboard need a vertex shader that rotates them towards the camera.
+/// Their StandardMaterial is replaced by a LeafBillboardMaterial based on it (and restored if the leaves are not billboards anymore).
+/// 
+/// Only trees with changed settings or a new leaf material are checked (and trees whose leaf material just finished loading).
+#[allow(clippy::too_many_arguments)]
+fn update_leaf_billboard_materials(
+    mut events: EventReader<AssetEvent<StandardMaterial>>,
+    trees: Query<(Entity, &Tree, &Leaves)>,
+    changed_trees: ChangedLeafTrees,
+    changed_leaf_materials: ChangedLeafMaterials,
+    leaf_materials: Query<(&ChildOf, &MeshMaterial3d<StandardMaterial>)>,
+    leaves: LeafMaterials,
+    global_tree_settings: Res<TreeMeshSettings>,
+    standard_materials: Res<Assets<StandardMaterial>>,
+    mut billboard_materials: ResMut<Assets<LeafBillboardMaterial>>,
+    mut commands: Commands,
+) {
+    let loaded: Vec<AssetId<StandardMaterial>> = events.read().filter_map(|event| match event {
+        AssetEvent::Added { id } | AssetEvent::LoadedWithDependencies { id } => Some(*id),
+        _ => None,
+    }).collect();
+
+    let mut tree_entities: Vec<Entity> = if global_tree_settings.is_changed() {
+        trees.iter().map(|(tree_entity, _, _)| tree_entity).collect()
+    } else {
+        changed_trees.iter().chain(changed_leaf_materials.iter().map(ChildOf::parent)).collect()
+    };
+    if !loaded.is_empty() {
+        tree_entities.extend(leaf_materials.iter().filter(|(_, material)| loaded.contains(&material.0.id())).map(|(child_of, _)| child_of.parent()));
+    }
+    tree_entities.sort_unstable();
+    tree_entities.dedup();
+
+    for (_, tree, leaves_entity) in trees.iter_many(tree_entities) {
+        let tree_settings: &TreeMeshSettings = match tree.tree_mesh_settings_override {
+            Some(ref tree_settings) => tree_settings,
+            None => global_tree_settings.as_ref(),
+        };
+        let Ok((standard_material, billboard_source, billboard_material)) = leaves.get(leaves_entity.0) else {
+            continue;
+        };
+        let extension = LeafBillboardExtension::from_normal_mode(tree_settings.leaves.normal_mode);
+
+        match (tree_settings.leaves.leaf_billboard == LeafBillboard::Billboard, standard_material, billboard_source) {
+            // (new) StandardMaterial on billboard leaves
+            (true, Some(standard_material), _) => {
+                let Some(base) = standard_materials.get(&standard_material.0) else {
+                    continue;
+                };
+                let billboard_material = billboard_materials.add(LeafBillboardMaterial {
+                    base: base.clone(),
+                    extension,
+                });
+                commands.entity(leaves_entity.0)
+                    .remove::<MeshMaterial3d<StandardMaterial>>()
+                    .insert((MeshMaterial3d(billboard_material), LeafBillboardSource(standard_material.clone())));
+            },
+            // billboard leaves (only touched if their normal mode changed, every change of the material is sent as an event)
+            (true, None, Some(_)) => {
+                let Some(billboard_material) = billboard_material else {
+                    continue;
+                };
+                let normal_mode_changed = billboard_materials.get(&billboard_material.0).is_some_and(|material| material.extension.normal_blend != extension.normal_blend);
+                if let Some(material) = billboard_materials.get_mut(&billboard_material.0).filter(|_| normal_mode_changed) {
+                    material.extension = extension;
+                }
+            },
+            // the leaves are not billboards anymore
+            (false, standard_material, Some(billboard_source)) => {
+                let mut leaves_commands = commands.entity(leaves_entity.0);
+                leaves_commands.remove::<(MeshMaterial3d<LeafBillboardMaterial>, LeafBillboardSource)>();
+                if standard_material.is_none() {
+                    leaves_commands.insert(billboard_source.0.clone());
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Changes of a leaf StandardMaterial are copied to the LeafBillboardMaterials created from it
+fn sync_leaf_billboard_materials(
+    mut events: EventReader<AssetEvent<StandardMaterial>>,
+    leaves: Query<(&LeafBillboardSource, &MeshMaterial3d<LeafBillboardMaterial>)>,
+    standard_materials: Res<Assets<StandardMaterial>>,
+    mut billboard_materials: ResMut<Assets<LeafBillboardMaterial>>,
+) {
+    for event in events.read() {
+        let AssetEvent::Modified { id } = event else {
+            continue;
+        };
+        let Some(base) = standard_materials.get(*id) else {
+            continue;
+        };
+        for (_, billboard_material) in leaves.iter().filter(|(source, _)| source.0.id() == *id) {
+            if let Some(material) = billboard_materials.get_mut(&billboard_material.0) {
+                material.base = base.clone();
+            }
+        }
+    }
+}
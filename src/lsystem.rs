@@ -0,0 +1,401 @@
+use bevy::prelude::*;
+use fastrand::Rng;
+
+use crate::{errors::LSystemError, meshgen::SkeletonNode, settings::{LSystemParams, LSystemRule}};
+
+/// the derivation fails if the string gets longer (protects against exploding rules)
+const MAX_MODULES: usize = 100_000;
+
+/// a symbol of the derived string with its parameters
+#[derive(Debug, Clone)]
+struct Module {
+    symbol: char,
+    parameters: Vec<f32>,
+}
+
+/// a symbol of a successor (or the axiom) with the expressions of its parameters
+#[derive(Debug, Clone)]
+struct ModuleTemplate {
+    symbol: char,
+    parameters: Vec<Expression>,
+}
+
+impl ModuleTemplate {
+    fn evaluate(&self, arguments: &[f32]) -> Module {
+        Module {
+            symbol: self.symbol,
+            parameters: self.parameters.iter().map(|parameter| parameter.evaluate(arguments)).collect(),
+        }
+    }
+}
+
+/// arithmetic expression over the parameters of the predecessor
+#[derive(Debug, Clone)]
+enum Expression {
+    Number(f32),
+    /// index of the parameter of the predecessor
+    Variable(usize),
+    Negate(Box<Expression>),
+    Binary(Box<Expression>, char, Box<Expression>),
+}
+
+impl Expression {
+    fn evaluate(&self, arguments: &[f32]) -> f32 {
+        match self {
+            Expression::Number(value) => *value,
+            Expression::Variable(index) => arguments[*index],
+            Expression::Negate(expression) => -expression.evaluate(arguments),
+            Expression::Binary(left, operator, right) => {
+                let (left, right) = (left.evaluate(arguments), right.evaluate(arguments));
+                match operator {
+                    '+' => left + right,
+                    '-' => left - right,
+                    '*' => left * right,
+                    _ => left / right,
+                }
+            },
+        }
+    }
+}
+
+/// comparison of two expressions (the condition of a parametric rule)
+#[derive(Debug, Clone)]
+struct Condition {
+    left: Expression,
+    comparison: &'static str,
+    right: Expression,
+}
+
+impl Condition {
+    fn evaluate(&self, arguments: &[f32]) -> bool {
+        let (left, right) = (self.left.evaluate(arguments), self.right.evaluate(arguments));
+        match self.comparison {
+            "<" => left < right,
+            "<=" => left <= right,
+            ">" => left > right,
+            ">=" => left >= right,
+            "==" => left == right,
+            _ => left != right,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CompiledRule {
+    symbol: char,
+    /// amount of parameters of the predecessor
+    arity: usize,
+    condition: Option<Condition>,
+    successor: Vec<ModuleTemplate>,
+    probability: f32,
+}
+
+impl CompiledRule {
+    fn compile(rule: &LSystemRule) -> Result<Self, LSystemError> {
+        let mut parser = Parser::new(&rule.predecessor, &[]);
+        let (symbol, variables) = parser.parse_predecessor()?;
+        let condition = if rule.condition.trim().is_empty() {
+            None
+        } else {
+            Some(Parser::new(&rule.condition, &variables).parse_condition()?)
+        };
+        Ok(Self {
+            symbol,
+            arity: variables.len(),
+            condition,
+            successor: Parser::new(&rule.successor, &variables).parse_modules()?,
+            probability: rule.probability.max(0.0),
+        })
+    }
+
+    fn matches(&self, module: &Module) -> bool {
+        self.symbol == module.symbol
+            && self.arity == module.parameters.len()
+            && self.condition.as_ref().is_none_or(|condition| condition.evaluate(&module.parameters))
+    }
+}
+
+/// recursive descent parser of modules, predecessors and conditions
+struct Parser<'a> {
+    chars: Vec<char>,
+    position: usize,
+    /// names of the parameters of the predecessor
+    variables: &'a [String],
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &str, variables: &'a [String]) -> Self {
+        Self { chars: text.chars().collect(), position: 0, variables }
+    }
+
+    fn error(&self, message: &str) -> LSystemError {
+        LSystemError(format!("{message} at position {} of \"{}\"", self.position, self.chars.iter().collect::<String>()))
+    }
+
+    /// next character (skipping whitespace) without consuming it
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.get(self.position).is_some_and(|c| c.is_whitespace()) {
+            self.position += 1;
+        }
+        self.chars.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let next = self.peek();
+        self.position += usize::from(next.is_some());
+        next
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), LSystemError> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            _ => Err(self.error(&format!("expected '{expected}'"))),
+        }
+    }
+
+    fn expect_end(&mut self) -> Result<(), LSystemError> {
+        match self.peek() {
+            None => Ok(()),
+            Some(c) => Err(self.error(&format!("unexpected '{c}'"))),
+        }
+    }
+
+    fn parse_identifier(&mut self) -> Result<String, LSystemError> {
+        if !self.peek().is_some_and(|c| c.is_alphabetic() || c == '_') {
+            return Err(self.error("expected a parameter name"));
+        }
+        let start = self.position;
+        while self.chars.get(self.position).is_some_and(|c| c.is_alphanumeric() || *c == '_') {
+            self.position += 1;
+        }
+        Ok(self.chars[start..self.position].iter().collect())
+    }
+
+    /// i.e. "A(l,w)"
+    fn parse_predecessor(&mut self) -> Result<(char, Vec<String>), LSystemError> {
+        let symbol = self.next().ok_or_else(|| self.error("empty predecessor"))?;
+        let mut variables = Vec::new();
+        if self.peek() == Some('(') {
+            self.next();
+            loop {
+                variables.push(self.parse_identifier()?);
+                match self.next() {
+                    Some(',') => continue,
+                    Some(')') => break,
+                    _ => return Err(self.error("expected ',' or ')'")),
+                }
+            }
+        }
+        self.expect_end()?;
+        Ok((symbol, variables))
+    }
+
+    /// i.e. "F(l*0.8)[+(30)A(l/2)]"
+    fn parse_modules(&mut self) -> Result<Vec<ModuleTemplate>, LSystemError> {
+        let mut modules = Vec::new();
+        while let Some(symbol) = self.next() {
+            if matches!(symbol, '(' | ')' | ',') {
+                return Err(self.error(&format!("unexpected '{symbol}'")));
+            }
+            let mut parameters = Vec::new();
+            if self.peek() == Some('(') {
+                self.next();
+                loop {
+                    parameters.push(self.parse_expression()?);
+                    match self.next() {
+                        Some(',') => continue,
+                        Some(')') => break,
+                        _ => return Err(self.error("expected ',' or ')'")),
+                    }
+                }
+            }
+            modules.push(ModuleTemplate { symbol, parameters });
+        }
+        Ok(modules)
+    }
+
+    /// i.e. "l > 0.5"
+    fn parse_condition(&mut self) -> Result<Condition, LSystemError> {
+        let left = self.parse_expression()?;
+        let comparison = match (self.next(), self.chars.get(self.position) == Some(&'=')) {
+            (Some('<'), true) => "<=",
+            (Some('<'), false) => "<",
+            (Some('>'), true) => ">=",
+            (Some('>'), false) => ">",
+            (Some('='), true) => "==",
+            (Some('!'), true) => "!=",
+            _ => return Err(self.error("expected a comparison")),
+        };
+        if comparison.len() == 2 {
+            self.position += 1;
+        }
+        let right = self.parse_expression()?;
+        self.expect_end()?;
+        Ok(Condition { left, comparison, right })
+    }
+
+    /// sum of terms
+    fn parse_expression(&mut self) -> Result<Expression, LSystemError> {
+        let mut expression = self.parse_term()?;
+        while let Some(operator @ ('+' | '-')) = self.peek() {
+            self.next();
+            expression = Expression::Binary(Box::new(expression), operator, Box::new(self.parse_term()?));
+        }
+        Ok(expression)
+    }
+
+    /// product of factors
+    fn parse_term(&mut self) -> Result<Expression, LSystemError> {
+        let mut expression = self.parse_factor()?;
+        while let Some(operator @ ('*' | '/')) = self.peek() {
+            self.next();
+            expression = Expression::Binary(Box::new(expression), operator, Box::new(self.parse_factor()?));
+        }
+        Ok(expression)
+    }
+
+    fn parse_factor(&mut self) -> Result<Expression, LSystemError> {
+        match self.peek() {
+            Some('-') => {
+                self.next();
+                Ok(Expression::Negate(Box::new(self.parse_factor()?)))
+            },
+            Some('(') => {
+                self.next();
+                let expression = self.parse_expression()?;
+                self.expect(')')?;
+                Ok(expression)
+            },
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let start = self.position;
+                while self.chars.get(self.position).is_some_and(|c| c.is_ascii_digit() || *c == '.') {
+                    self.position += 1;
+                }
+                let number: String = self.chars[start..self.position].iter().collect();
+                number.parse().map(Expression::Number).map_err(|_| self.error("invalid number"))
+            },
+            _ => {
+                let name = self.parse_identifier()?;
+                match self.variables.iter().position(|variable| *variable == name) {
+                    Some(index) => Ok(Expression::Variable(index)),
+                    None => Err(self.error(&format!("unknown parameter '{name}'"))),
+                }
+            },
+        }
+    }
+}
+
+/// position and orientation of a leaf placed by the turtle (with the last node drawn by the turtle)
+type TurtleLeaf = (Vec3, Quat, Option<usize>);
+
+/// state of the turtle (saved with '[' and restored with ']')
+#[derive(Debug, Clone)]
+struct Turtle {
+    position: Vec3,
+    orientation: Quat,
+    radius: f32,
+    /// last node drawn by this turtle (none after moving without drawing)
+    node: Option<usize>,
+    /// depth of the brackets
+    level: usize,
+}
+
+/// Derive the string of the L-system and interpret it with a turtle
+///
+/// Returns the nodes of the skeleton (see GenerationAlgorithm) and the leaves.
+pub(crate) fn generate_skeleton(params: &LSystemParams, base_radius: f32, rng: &mut Rng) -> Result<(Vec<SkeletonNode>, Vec<TurtleLeaf>), LSystemError> {
+    let rules = params.rules.iter().map(CompiledRule::compile).collect::<Result<Vec<_>, _>>()?;
+    let mut modules: Vec<Module> = Parser::new(&params.axiom, &[]).parse_modules()?.iter().map(|module| module.evaluate(&[])).collect();
+
+    for iteration in 0..params.iterations {
+        modules = derive(&modules, &rules, rng).ok_or_else(|| LSystemError(format!(
+            "more than {MAX_MODULES} modules in iteration {}: please reduce the iterations or the successors of the rules", iteration + 1
+        )))?;
+    }
+
+    Ok(interpret(params, base_radius, &modules))
+}
+
+/// replace each module by the successor of a matching rule (stochastic rules are picked by their probability)
+/// 
+/// none if the derived string gets longer than MAX_MODULES
+fn derive(modules: &[Module], rules: &[CompiledRule], rng: &mut Rng) -> Option<Vec<Module>> {
+    let mut derived = Vec::with_capacity(modules.len() * 2);
+    for module in modules {
+        let candidates: Vec<&CompiledRule> = rules.iter().filter(|rule| rule.matches(module)).collect();
+        let rule = match candidates.len() {
+            0 => None,
+            1 => Some(candidates[0]),
+            _ => {
+                let total: f32 = candidates.iter().map(|rule| rule.probability).sum();
+                let mut pick = rng.f32() * total;
+                candidates.iter().find(|rule| {
+                    pick -= rule.probability;
+                    pick < 0.0
+                }).or(candidates.last()).copied()
+            },
+        };
+        match rule {
+            Some(rule) => derived.extend(rule.successor.iter().map(|successor| successor.evaluate(&module.parameters))),
+            None => derived.push(module.clone()),
+        }
+        if derived.len() > MAX_MODULES {
+            return None;
+        }
+    }
+    Some(derived)
+}
+
+/// turtle interpretation (the turtle heads along its local y-axis):
+/// F(l) draw forward, f(l) move forward, +(a) -(a) turn, &(a) ^(a) pitch, \(a) /(a) roll, | turn around,
+/// [ ] push/pop the turtle, !(r) set the radius (without parameter: multiply by width_decay), L leaf
+fn interpret(params: &LSystemParams, base_radius: f32, modules: &[Module]) -> (Vec<SkeletonNode>, Vec<TurtleLeaf>) {
+    let mut nodes: Vec<SkeletonNode> = Vec::new();
+    let mut leaves: Vec<TurtleLeaf> = Vec::new();
+    let mut stack: Vec<Turtle> = Vec::new();
+    let mut turtle = Turtle { position: Vec3::ZERO, orientation: Quat::IDENTITY, radius: base_radius, node: None, level: 0 };
+
+    for module in modules {
+        let length = module.parameters.first().copied().unwrap_or(params.step_length);
+        let angle = module.parameters.first().copied().unwrap_or(params.angle).to_radians();
+        match module.symbol {
+            'F' => {
+                let parent = match turtle.node {
+                    Some(node) => node,
+                    None => {
+                        nodes.push(SkeletonNode { position: turtle.position, parent: None, radius: turtle.radius, level: turtle.level });
+                        nodes.len() - 1
+                    },
+                };
+                turtle.position += turtle.orientation * Vec3::Y * length;
+                nodes.push(SkeletonNode { position: turtle.position, parent: Some(parent), radius: turtle.radius, level: turtle.level });
+                turtle.node = Some(nodes.len() - 1);
+            },
+            'f' => {
+                turtle.position += turtle.orientation * Vec3::Y * length;
+                turtle.node = None;
+            },
+            '+' => turtle.orientation *= Quat::from_rotation_z(angle),
+            '-' => turtle.orientation *= Quat::from_rotation_z(-angle),
+            '&' => turtle.orientation *= Quat::from_rotation_x(angle),
+            '^' => turtle.orientation *= Quat::from_rotation_x(-angle),
+            '\\' => turtle.orientation *= Quat::from_rotation_y(angle),
+            '/' => turtle.orientation *= Quat::from_rotation_y(-angle),
+            '|' => turtle.orientation *= Quat::from_rotation_z(std::f32::consts::PI),
+            '[' => {
+                stack.push(turtle.clone());
+                turtle.level += 1;
+            },
+            ']' => {
+                if let Some(saved) = stack.pop() {
+                    turtle = saved;
+                }
+            },
+            '!' => turtle.radius = module.parameters.first().copied().unwrap_or(turtle.radius * params.width_decay),
+            'L' => leaves.push((turtle.position, turtle.orientation, turtle.node)),
+            _ => {},
+        }
+    }
+
+    (nodes, leaves)
+}
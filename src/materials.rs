@@ -0,0 +1,76 @@
+use bevy::{
+    asset::{load_internal_asset, weak_handle},
+    pbr::{ExtendedMaterial, MaterialExtension, MaterialExtensionKey, MaterialExtensionPipeline},
+    prelude::*,
+    render::{mesh::MeshVertexBufferLayoutRef, render_resource::{AsBindGroup, RenderPipelineDescriptor, ShaderRef, SpecializedMeshPipelineError}},
+};
+
+use crate::{enums::LeafNormalMode, meshgen::{ATTRIBUTE_LEAF_CENTER, ATTRIBUTE_LEAF_CORNER}};
+
+const LEAF_BILLBOARD_SHADER_HANDLE: Handle<Shader> = weak_handle!("6f0c1a7e-3b52-4d8e-9a0c-2f6d4e1b7c93");
+
+/// shader locations of the additional vertex attributes (the default mesh pipeline uses the locations 0..7)
+const LEAF_CENTER_SHADER_LOCATION: u32 = 14;
+const LEAF_CORNER_SHADER_LOCATION: u32 = 15;
+
+/// A StandardMaterial with a vertex shader that rotates leaf quads towards the camera
+///
+/// Needs the leaf mesh of a tree generated with LeafBillboard::Billboard (see `ATTRIBUTE_LEAF_CENTER` and `ATTRIBUTE_LEAF_CORNER`).
+/// Meshes without these attributes are rendered like with the plain StandardMaterial.
+/// The TreeProceduralGenerationPlugin keeps the base material in sync with the leaf StandardMaterial it was created from.
+pub type LeafBillboardMaterial = ExtendedMaterial<StandardMaterial, LeafBillboardExtension>;
+
+#[derive(Asset, AsBindGroup, Reflect, Debug, Clone, Default)]
+pub struct LeafBillboardExtension {
+    /// blend of the normals from facing the camera like the rotated quad (0.0) to the normals of the mesh (1.0, i.e. pointing away from the crown)
+    #[uniform(100)]
+    pub normal_blend: f32,
+}
+
+impl LeafBillboardExtension {
+    /// normals facing the camera for LeafNormalMode::Flat (the normal of the unrotated card is meaningless), the spherical normals of the mesh otherwise
+    pub fn from_normal_mode(normal_mode: LeafNormalMode) -> Self {
+        let normal_blend = match normal_mode {
+            LeafNormalMode::Flat => 0.0,
+            LeafNormalMode::SphericalCrown | LeafNormalMode::SphericalBranch => 1.0,
+            LeafNormalMode::Blended(blend) => blend.clamp(0.0, 1.0),
+        };
+        Self { normal_blend }
+    }
+}
+
+impl MaterialExtension for LeafBillboardExtension {
+    fn vertex_shader() -> ShaderRef {
+        LEAF_BILLBOARD_SHADER_HANDLE.into()
+    }
+
+    fn prepass_vertex_shader() -> ShaderRef {
+        LEAF_BILLBOARD_SHADER_HANDLE.into()
+    }
+
+    fn specialize(
+        _pipeline: &MaterialExtensionPipeline,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayoutRef,
+        _key: MaterialExtensionKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        if layout.0.contains(ATTRIBUTE_LEAF_CENTER) && layout.0.contains(ATTRIBUTE_LEAF_CORNER) {
+            // append the billboard attributes to the attributes already chosen by the mesh pipeline
+            let billboard_layout = layout.0.get_layout(&[
+                ATTRIBUTE_LEAF_CENTER.at_shader_location(LEAF_CENTER_SHADER_LOCATION),
+                ATTRIBUTE_LEAF_CORNER.at_shader_location(LEAF_CORNER_SHADER_LOCATION),
+            ])?;
+            if let Some(vertex_buffer) = descriptor.vertex.buffers.first_mut() {
+                vertex_buffer.attributes.extend(billboard_layout.attributes);
+                descriptor.vertex.shader_defs.push("LEAF_BILLBOARD".into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// register the shaders and materials of this crate
+pub(crate) fn build_materials(app: &mut App) {
+    load_internal_asset!(app, LEAF_BILLBOARD_SHADER_HANDLE, "shaders/leaf_billboard.wgsl", Shader::from_wgsl);
+    app.add_plugins(MaterialPlugin::<LeafBillboardMaterial>::default());
+}
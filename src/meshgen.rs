@@ -1,11 +1,92 @@
 use core::f32;
 use std::f32::consts::PI;
 
-use bevy::{asset::RenderAssetUsages, prelude::*, render::mesh::{Indices, PrimitiveTopology}};
+use bevy::{asset::RenderAssetUsages, prelude::*, render::{mesh::{Indices, MeshVertexAttribute, PrimitiveTopology, VertexAttributeValues}, render_resource::VertexFormat}};
 use fastrand::Rng;
 
-use crate::{enums::TreeType, settings::TreeMeshSettings};
-use crate::errors::IndicesOverflowError;
+use crate::{enums::{BarkUvMode, BranchJointMode, CrossSection, EndCap, GenerationAlgorithm, GravityMode, LeafBillboard, LeafNormalMode, TreeType}, settings::{LeafAtlas, LeafShape, SeasonParams, TreeMeshSettings}};
+use crate::errors::{IndicesOverflowError, LeafShapeError};
+use crate::noise::{fbm, hash_index, value_noise};
+use crate::obstacles::{distance_to_obstacles, obstacle_normal, ray_hits_obstacles, Obstacle};
+use crate::occlusion::{bake_ambient_occlusion, bake_exposure, OcclusionSurface, ShadeGrid};
+use crate::lsystem;
+use crate::space_colonization::grow_skeleton;
+
+/// Center of the leaf quad this vertex belongs to (only generated for LeafBillboard::Billboard)
+pub const ATTRIBUTE_LEAF_CENTER: MeshVertexAttribute = MeshVertexAttribute::new("Leaf_Center", 1_839_472_011, VertexFormat::Float32x3);
+/// Offset of this vertex from the center of its leaf quad (x: right, y: up; only generated for LeafBillboard::Billboard)
+pub const ATTRIBUTE_LEAF_CORNER: MeshVertexAttribute = MeshVertexAttribute::new("Leaf_Corner", 1_839_472_012, VertexFormat::Float32x2);
+/// Point this vertex grows out of in the growth animation (only generated with TreeGenerationContext::growth_animation; see `morph_growth()`)
+pub const ATTRIBUTE_GROWTH_PIVOT: MeshVertexAttribute = MeshVertexAttribute::new("Growth_Pivot", 1_839_472_013, VertexFormat::Float32x3);
+/// Progress of the growth animation (0..1) at which this vertex starts (x) and finishes (y) moving from its pivot to its position
+pub const ATTRIBUTE_GROWTH_TIME: MeshVertexAttribute = MeshVertexAttribute::new("Growth_Time", 1_839_472_014, VertexFormat::Float32x2);
+/// Point the leaf this vertex belongs to is attached to (only generated with TreeGenerationContext::seasons; see `apply_season()`)
+pub const ATTRIBUTE_LEAF_PIVOT: MeshVertexAttribute = MeshVertexAttribute::new("Leaf_Pivot", 1_839_472_015, VertexFormat::Float32x3);
+/// Random value (0..1) of the leaf this vertex belongs to; stable for the same seed and settings (only generated with TreeGenerationContext::seasons)
+pub const ATTRIBUTE_LEAF_RANDOM: MeshVertexAttribute = MeshVertexAttribute::new("Leaf_Random", 1_839_472_016, VertexFormat::Float32);
+/// Baked ambient occlusion of this vertex (0 = fully occluded, 1 = unoccluded; only generated with AmbientOcclusionParams::enabled)
+pub const ATTRIBUTE_AMBIENT_OCCLUSION: MeshVertexAttribute = MeshVertexAttribute::new("Ambient_Occlusion", 1_839_472_017, VertexFormat::Float32);
+/// Masks of this vertex (x: snow, y: moss; 0..1) to blend material variants (only generated with SurfaceMaskParams::enabled)
+pub const ATTRIBUTE_SURFACE_MASKS: MeshVertexAttribute = MeshVertexAttribute::new("Surface_Masks", 1_839_472_018, VertexFormat::Float32x2);
+
+/// part of the growth animation in which the leaves unfold (after their branch is fully grown)
+const LEAF_UNFOLD_TIME: f32 = 0.15;
+/// time of the year at which the first leaves fall (middle of autumn)
+const LEAF_DROP_TIME: f32 = 0.5;
+/// time of the year at which new leaves start to grow (end of winter)
+const LEAF_BUD_TIME: f32 = 0.9;
+/// part of the year in which a single leaf shrinks while falling (see SeasonParams::shrink)
+const LEAF_SHRINK_TIME: f32 = 0.05;
+/// upper limit of SeasonParams::timing_variation (a quarter of the year)
+const MAX_LEAF_TIMING_VARIATION: f32 = 0.25;
+/// size of the cells of the density grid sheltering the surfaces from snow in meters
+const SNOW_SHELTER_CELL_SIZE: f32 = 0.25;
+
+/// amount of harmonics of noisy cross-sections
+const NOISE_HARMONICS: usize = 3;
+/// rotation (radians) of the noise of noisy cross-sections per ring
+const NOISE_RING_DRIFT: f32 = 0.35;
+
+/// offset of the seed of the knot noise (to be independent from the ridges)
+const KNOT_SEED_OFFSET: u32 = 0x5bd1_e995;
+
+/// amount of rings of EndCap::Dome (without the center vertex)
+const END_CAP_DOME_RINGS: usize = 3;
+
+/// how much avoiding branches are pushed away from obstacles (in addition to sliding along them)
+const AVOIDANCE_PUSH: f32 = 0.3;
+
+/// how fast branches bend towards the light per section (multiplied by Phototropism::strength)
+const PHOTOTROPISM_PER_SECTION: f32 = 0.1;
+/// cell size of the grid of the shade cast by the branches and leaves (see Phototropism::shade_length_reduction)
+const SHADE_CELL_SIZE: f32 = 0.5;
+/// how far the shade of the tree and the obstacles reaches
+const SHADE_DISTANCE: f32 = 20.0;
+
+/// resistance of the branches against gravity relative to their radius
+const GRAVITY_STIFFNESS: f32 = 100.0;
+
+/// amount of samples along a palm frond
+const FROND_SAMPLES: usize = 8;
+/// angle between consecutive palm fronds (radians)
+const GOLDEN_ANGLE: f32 = 2.399_963;
+
+/// amount of co-dominant stems of a forked trunk
+const FORK_STEMS: f32 = 2.0;
+
+/// how fast surface roots turn downwards (slerp factor per section)
+const ROOT_DIVE_PER_SECTION: f32 = 0.04;
+
+/// amount of crossed strips per needle cluster
+const NEEDLE_STRIPS_PER_CLUSTER: usize = 3;
+/// width of a needle strip relative to the leaf size
+const NEEDLE_STRIP_WIDTH: f32 = 0.15;
+/// amount of points along the arc of a scale-leaf fan
+const SCALE_FAN_ARC_POINTS: usize = 7;
+/// half opening angle of a scale-leaf fan in degrees
+const SCALE_FAN_ANGLE: f32 = 50.0;
+/// LeafNormalMode::SphericalBranch: depth of the center of the normals behind the leaf card (relative to the distance of the vertex from its pivot)
+const SPHERICAL_BRANCH_DEPTH: f32 = 1.0;
 
 #[derive(Debug, Clone)]
 struct BranchGenState {
@@ -20,6 +101,38 @@ struct BranchGenState {
     pub recursion_count: usize,
     pub sections: usize,
     pub segments: usize,
+    /// v-coordinate of the bark texture at the start of this branch
+    pub v_start: f32,
+    /// where this branch is attached to its parent branch (none for the trunk and its internal parts)
+    pub parent_joint: Option<ParentJoint>,
+    /// surface roots have neither child branches nor leaves
+    pub is_root: bool,
+    /// seed of the bark displacement noise (same for the whole tree, so the internal parts of the trunk fit together)
+    pub bark_seed: u32,
+    /// how often the trunk has already forked into co-dominant stems
+    pub fork_depth: u8,
+    /// index of the first section (forked stems continue the sections of their trunk)
+    pub first_section: usize,
+    /// index of the first ring of this branch along the whole trunk (the internal parts of the trunk continue the rings of the previous part)
+    pub first_ring: usize,
+    /// phases of the cross-section noise (drawn once per branch, so that the internal parts and forked stems of the trunk fit together)
+    pub noise_phases: [f32; NOISE_HARMONICS],
+    /// age of the tree (0..1) at which this branch starts growing
+    pub birth: f32,
+    /// first vertex of the last ring of the previous part of the trunk (at the same place as the first ring of this part)
+    pub seam_ring: Option<usize>,
+    /// origin of this branch in the tree at the current age (see TreeGenerationContext::age)
+    pub aged_origin: Vec3,
+}
+
+/// the (locally cylindrical) part of the parent branch a child branch grows from
+#[derive(Debug, Clone)]
+struct ParentJoint {
+    /// point on the axis of the parent branch
+    pub origin: Vec3,
+    /// direction of the axis of the parent branch (normalized)
+    pub axis: Vec3,
+    pub radius: f32,
 }
 
 #[derive(Debug, Clone)]
@@ -29,13 +142,74 @@ struct SectionData {
     pub radius: f32
 }
 
+impl MeshAttributes {
+    /// amount of vertices and indices (to find the newly generated ones later)
+    fn first_new(&self) -> (usize, usize) {
+        (self.positions.len(), self.indices.len())
+    }
+
+    /// remove all vertices and indices after the given amounts
+    fn truncate(&mut self, vertices: usize, indices: usize) {
+        self.positions.truncate(vertices);
+        self.normals.truncate(vertices);
+        self.uvs.truncate(vertices);
+        self.leaf_centers.truncate(vertices);
+        self.leaf_corners.truncate(vertices);
+        self.growth_pivots.truncate(vertices);
+        self.growth_times.truncate(vertices);
+        self.leaf_randoms.truncate(vertices);
+        self.levels.truncate(vertices);
+        self.indices.truncate(indices);
+    }
+
+    /// give all vertices without growth animation data (i.e. end caps) the data of the last vertex with it
+    fn extend_growth(&mut self) {
+        let vertices = self.positions.len();
+        if let (Some(pivot), Some(time)) = (self.growth_pivots.last().copied(), self.growth_times.last().copied()) {
+            self.growth_pivots.resize(vertices, pivot);
+            self.growth_times.resize(vertices, time);
+        }
+    }
+
+    /// set the growth animation time of all vertices without one (i.e. the leaves generated since the last call)
+    fn set_growth_time(&mut self, time: [f32; 2]) {
+        self.growth_times.resize(self.positions.len(), time);
+    }
+
+    /// set the level of all vertices without one (i.e. the branch generated since the last call)
+    fn set_level(&mut self, level: usize) {
+        self.levels.resize(self.positions.len(), level);
+    }
+}
+
+/// a node of a tree skeleton of the alternative generation algorithms (see GenerationAlgorithm)
+#[derive(Debug, Clone)]
+pub(crate) struct SkeletonNode {
+    pub position: Vec3,
+    /// index of the parent node (none for the base of the trunk)
+    pub parent: Option<usize>,
+    pub radius: f32,
+    /// preferred nesting of the branches (the thickest child of the lowest level continues the branch of its parent)
+    pub level: usize,
+}
+
 #[cfg(not(feature = "u32_indices"))]
 #[derive(Debug, Default)]
 struct MeshAttributes {
     positions: Vec<[f32; 3]>,
     normals: Vec<[f32; 3]>,
     uvs: Vec<[f32; 2]>,
-    indices: Vec<u16>
+    indices: Vec<u16>,
+    leaf_centers: Vec<[f32; 3]>,
+    leaf_corners: Vec<[f32; 2]>,
+    growth_pivots: Vec<[f32; 3]>,
+    growth_times: Vec<[f32; 2]>,
+    leaf_randoms: Vec<f32>,
+    /// level of the branch of each vertex (see BranchGenState::recursion_count)
+    levels: Vec<usize>,
+    /// amount of leaves generated so far and the seed of their random values (the order of the leaves is deterministic)
+    leaf_count: u32,
+    leaf_seed: u32,
 }
 
 #[cfg(feature = "u32_indices")]
@@ -44,14 +218,63 @@ struct MeshAttributes {
     positions: Vec<[f32; 3]>,
     normals: Vec<[f32; 3]>,
     uvs: Vec<[f32; 2]>,
-    indices: Vec<u32>
+    indices: Vec<u32>,
+    leaf_centers: Vec<[f32; 3]>,
+    leaf_corners: Vec<[f32; 2]>,
+    growth_pivots: Vec<[f32; 3]>,
+    growth_times: Vec<[f32; 2]>,
+    leaf_randoms: Vec<f32>,
+    /// level of the branch of each vertex (see BranchGenState::recursion_count)
+    levels: Vec<usize>,
+    /// amount of leaves generated so far and the seed of their random values (the order of the leaves is deterministic)
+    leaf_count: u32,
+    leaf_seed: u32,
+}
+
+/// Additional inputs of the generation which are not part of the TreeMeshSettings (i.e. the surroundings of a single tree)
+#[derive(Clone, Debug)]
+pub struct TreeGenerationContext {
+    /// branches bend away from (or stop at) these obstacles; in the local space of the tree (see ObstacleParams)
+    pub obstacles: Vec<Obstacle>,
+    /// age of the tree from 0.0 (sapling) to 1.0 (fully grown): branches lengthen and thicken, new levels and leaves appear
+    /// 
+    /// the recursive algorithm grows the same tree (same seed and settings) at all ages
+    pub age: f32,
+    /// add the vertex attributes of the growth animation to both meshes (see `ATTRIBUTE_GROWTH_PIVOT`, `ATTRIBUTE_GROWTH_TIME` and `morph_growth()`)
+    pub growth_animation: bool,
+    /// add the vertex attributes of the seasons to the leaves mesh (see `ATTRIBUTE_LEAF_PIVOT`, `ATTRIBUTE_LEAF_RANDOM` and `apply_season()`)
+    pub seasons: bool,
+}
+
+impl Default for TreeGenerationContext {
+    fn default() -> Self {
+        Self {
+            obstacles: Vec::new(),
+            age: 1.0,
+            growth_animation: false,
+            seasons: false,
+        }
+    }
 }
 
 /// Generate two meshes: the trunk/branches and the leaves
 /// 
 /// Both meshes together represent a tree. The mesh is built according to the provided TreeMeshSettings.
 /// If the tree should be reproduced use the same settings and the same Rng (including the same seed).
-pub fn generate_tree_meshes(settings: &TreeMeshSettings, rng: &mut Rng) -> Result<(Mesh, Mesh), BevyError> { 
+pub fn generate_tree_meshes(settings: &TreeMeshSettings, rng: &mut Rng) -> Result<(Mesh, Mesh), BevyError> {
+    generate_tree_meshes_with_context(settings, &TreeGenerationContext::default(), rng)
+}
+
+/// Generate two meshes (see `generate_tree_meshes`) taking the surroundings of the tree into account
+/// 
+/// Seed stability: optional features only draw from the rng if they are enabled, so the same seed still gives the same tree
+/// for all settings which do not use them. The rng is also used in the same way at every age of the tree
+/// (branches which did not start to grow yet are generated and discarded).
+pub fn generate_tree_meshes_with_context(settings: &TreeMeshSettings, context: &TreeGenerationContext, rng: &mut Rng) -> Result<(Mesh, Mesh), BevyError> { 
+    // shrubs: smaller stems with less levels of branches
+    let shrub_settings = (settings.tree_type == TreeType::Shrub).then(|| shrub_stem_settings(settings));
+    let settings = shrub_settings.as_ref().unwrap_or(settings);
+
     let state: BranchGenState = BranchGenState {
         origin: Vec3::ZERO,
         orientation: Quat::IDENTITY,
@@ -64,40 +287,279 @@ pub fn generate_tree_meshes(settings: &TreeMeshSettings, rng: &mut Rng) -> Resul
         recursion_count: 0,
         sections: settings.branch.sections[0] as usize,
         segments: settings.branch.segments[0] as usize,
+        v_start: 0.0,
+        parent_joint: None,
+        is_root: false,
+        bark_seed: if settings.branch.displacement.enabled { rng.u32(..) } else { 0 },
+        fork_depth: 0,
+        first_section: 0,
+        first_ring: 0,
+        noise_phases: noise_phases(settings, 0, rng),
+        birth: 0.0,
+        aged_origin: Vec3::ZERO,
+        seam_ring: None,
+    };
+    let trunks = generate_trunks(settings, state, rng);
+    generate_branches_internal(settings, context, trunks, rng)
+}
+
+/// Morph a mesh generated with TreeGenerationContext::growth_animation to a progress of its growth animation (0..1)
+/// 
+/// Every vertex moves from its growth pivot to its position while the progress passes its growth time.
+/// Meshes without the growth attributes are copied unchanged.
+/// A vertex shader can do the same on the GPU: `mix(pivot, position, saturate((progress - time.x) / (time.y - time.x)))`
+pub fn morph_growth(mesh: &Mesh, progress: f32) -> Mesh {
+    let mut morphed = mesh.clone();
+    let (Some(VertexAttributeValues::Float32x3(pivots)), Some(VertexAttributeValues::Float32x2(times))) = (mesh.attribute(ATTRIBUTE_GROWTH_PIVOT), mesh.attribute(ATTRIBUTE_GROWTH_TIME)) else {
+        return morphed;
+    };
+
+    let factors: Vec<f32> = times.iter().map(|[start, end]| {
+        if end - start > f32::EPSILON {
+            ((progress - start) / (end - start)).clamp(0.0, 1.0)
+        } else if progress > *end || progress >= 1.0 {
+            1.0
+        } else {
+            0.0
+        }
+    }).collect();
+    let morph = |values: &[[f32; 3]]| -> Vec<[f32; 3]> {
+        values.iter().zip(pivots).zip(&factors)
+            .map(|((value, pivot), factor)| Vec3::from_array(*pivot).lerp(Vec3::from_array(*value), *factor).to_array())
+            .collect()
+    };
+
+    if let Some(VertexAttributeValues::Float32x3(positions)) = mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+        morphed.insert_attribute(Mesh::ATTRIBUTE_POSITION, morph(positions));
+    }
+    // billboard leaves: the center grows like a position and the quad unfolds around it
+    if let Some(VertexAttributeValues::Float32x3(centers)) = mesh.attribute(ATTRIBUTE_LEAF_CENTER) {
+        morphed.insert_attribute(ATTRIBUTE_LEAF_CENTER, morph(centers));
+    }
+    if let Some(VertexAttributeValues::Float32x2(corners)) = mesh.attribute(ATTRIBUTE_LEAF_CORNER) {
+        let corners: Vec<[f32; 2]> = corners.iter().zip(&factors).map(|([x, y], factor)| [x * factor, y * factor]).collect();
+        morphed.insert_attribute(ATTRIBUTE_LEAF_CORNER, corners);
+    }
+    morphed
+}
+
+/// Color the leaves of a leaves mesh generated with TreeGenerationContext::seasons for a time of the year and let them fall in autumn
+/// 
+/// The time of the year: 0.0 = spring, 0.25 = summer, 0.5 = autumn, 0.75 = winter (wraps around).
+/// Every leaf gets its own color, delay and time to fall from its random value, so the same leaves change and fall first at every time of the year.
+/// The colors are written to `Mesh::ATTRIBUTE_COLOR` (multiplied with the existing vertex colors); fallen leaves are removed from the indices.
+/// Meshes without the season attributes are copied unchanged.
+pub fn apply_season(leaves_mesh: &Mesh, time: f32, params: &SeasonParams) -> Mesh {
+    let mut seasoned = leaves_mesh.clone();
+    let (Some(VertexAttributeValues::Float32x3(pivots)), Some(VertexAttributeValues::Float32(randoms))) = (leaves_mesh.attribute(ATTRIBUTE_LEAF_PIVOT), leaves_mesh.attribute(ATTRIBUTE_LEAF_RANDOM)) else {
+        return seasoned;
+    };
+
+    let (spring, summer, winter) = (linear_color(params.spring_color), linear_color(params.summer_color), linear_color(params.winter_color));
+    let autumn = params.autumn_colors.map(linear_color);
+    // the last leaf has to be fallen before the new leaves grow
+    let timing_variation = params.timing_variation.clamp(0.0, MAX_LEAF_TIMING_VARIATION);
+    let leaf_drop = params.leaf_drop.clamp(0.0, (LEAF_BUD_TIME - LEAF_DROP_TIME - LEAF_SHRINK_TIME - timing_variation).max(0.0));
+    let shrink_time = if params.shrink { LEAF_SHRINK_TIME } else { 0.0 };
+    let ramp = |t: f32, start: f32, end: f32| if end - start > f32::EPSILON { ((t - start) / (end - start)).clamp(0.0, 1.0) } else if t >= start { 1.0 } else { 0.0 };
+
+    let vertex_colors = match leaves_mesh.attribute(Mesh::ATTRIBUTE_COLOR) {
+        Some(VertexAttributeValues::Float32x4(colors)) => colors.clone(),
+        _ => vec![[1.0; 4]; randoms.len()],
+    };
+    let (colors, scales): (Vec<[f32; 4]>, Vec<f32>) = randoms.iter().zip(vertex_colors).map(|(random, vertex_color)| {
+        // more (uncorrelated) random values of the leaf
+        let (hue_random, brightness_random) = ((random * 613.0).fract(), (random * 7_919.0).fract());
+        let t = (time - random * timing_variation).rem_euclid(1.0);
+
+        let autumn = autumn[0].lerp(autumn[1], hue_random);
+        let color = match t {
+            t if t < 0.25 => spring.lerp(summer, t / 0.25),
+            t if t < 0.5 => summer.lerp(autumn, (t - 0.25) / 0.25),
+            t if t < 0.75 => autumn.lerp(winter, (t - 0.5) / 0.25),
+            t => winter.lerp(spring, (t - 0.75) / 0.25),
+        };
+        let brightness = 1.0 + (brightness_random * 2.0 - 1.0) * params.color_variation.max(0.0);
+        let color = ((color.truncate() * brightness).extend(color.w) * Vec4::from_array(vertex_color)).to_array();
+
+        let scale = if leaf_drop <= 0.0 {
+            1.0
+        } else if t >= LEAF_BUD_TIME {
+            ramp(t, LEAF_BUD_TIME, LEAF_BUD_TIME + shrink_time)
+        } else {
+            let drop_time = LEAF_DROP_TIME + random * leaf_drop;
+            1.0 - ramp(t, drop_time, drop_time + shrink_time)
+        };
+        (color, scale)
+    }).unzip();
+
+    if let Some(VertexAttributeValues::Float32x3(positions)) = leaves_mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+        let positions: Vec<[f32; 3]> = positions.iter().zip(pivots).zip(&scales)
+            .map(|((position, pivot), scale)| Vec3::from_array(*pivot).lerp(Vec3::from_array(*position), *scale).to_array())
+            .collect();
+        seasoned.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    }
+    // billboard leaves: the center shrinks like a position and the quad around it
+    if let Some(VertexAttributeValues::Float32x3(centers)) = leaves_mesh.attribute(ATTRIBUTE_LEAF_CENTER) {
+        let centers: Vec<[f32; 3]> = centers.iter().zip(pivots).zip(&scales)
+            .map(|((center, pivot), scale)| Vec3::from_array(*pivot).lerp(Vec3::from_array(*center), *scale).to_array())
+            .collect();
+        seasoned.insert_attribute(ATTRIBUTE_LEAF_CENTER, centers);
+    }
+    if let Some(VertexAttributeValues::Float32x2(corners)) = leaves_mesh.attribute(ATTRIBUTE_LEAF_CORNER) {
+        let corners: Vec<[f32; 2]> = corners.iter().zip(&scales).map(|([x, y], scale)| [x * scale, y * scale]).collect();
+        seasoned.insert_attribute(ATTRIBUTE_LEAF_CORNER, corners);
+    }
+    seasoned.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+
+    // fallen leaves
+    let is_visible = |a: usize, b: usize, c: usize| scales[a] > 0.0 || scales[b] > 0.0 || scales[c] > 0.0;
+    let visible_indices = match leaves_mesh.indices() {
+        Some(Indices::U16(indices)) => Some(Indices::U16(
+            indices.chunks_exact(3).filter(|triangle| is_visible(triangle[0] as usize, triangle[1] as usize, triangle[2] as usize)).flatten().copied().collect()
+        )),
+        Some(Indices::U32(indices)) => Some(Indices::U32(
+            indices.chunks_exact(3).filter(|triangle| is_visible(triangle[0] as usize, triangle[1] as usize, triangle[2] as usize)).flatten().copied().collect()
+        )),
+        None => None,
     };
-    generate_branches_internal(settings, state, rng)
+    if let Some(indices) = visible_indices {
+        seasoned.insert_indices(indices);
+    }
+    seasoned
 }
 
-fn generate_branches_internal(settings: &TreeMeshSettings, state: BranchGenState, rng: &mut Rng) -> Result<(Mesh, Mesh), BevyError> { 
+fn generate_branches_internal(settings: &TreeMeshSettings, context: &TreeGenerationContext, trunks: Vec<BranchGenState>, rng: &mut Rng) -> Result<(Mesh, Mesh), BevyError> { 
     // Allocate mesh attributes
     // TODO allocate just enough to reduce reallocations
     let mut branches_attributes: MeshAttributes = MeshAttributes::default();
-    let mut leaves_attributes: MeshAttributes = MeshAttributes::default();
-    //let mut branches_colors:    Vec<[f32; 4]> = Vec::new(); //with_capacity(rings * ring_stride);
+    let mut leaves_attributes: MeshAttributes = MeshAttributes {
+        // the state of the rng (without drawing from it)
+        leaf_seed: rng.get_seed() as u32,
+        ..Default::default()
+    };
+
+    let bark_seed = trunks.first().map_or(0, |trunk| trunk.bark_seed);
+    let mut shade_grid = ShadeGrid::new(SHADE_CELL_SIZE, settings.phototropism.direction);
+    match settings.algorithm {
+        GenerationAlgorithm::Recursive => {
+            for state in trunks {
+                recurse_a_branch(settings, context, state, rng, &mut shade_grid, &mut branches_attributes, &mut leaves_attributes)?;
+            }
+        },
+        GenerationAlgorithm::SpaceColonization => {
+            let (skeleton, _) = age_skeleton(&grow_skeleton(&settings.space_colonization, settings.branch.trunk_base_radius, context, rng)?, context.age);
+            generate_from_skeleton(settings, context, &skeleton, bark_seed, rng, &mut branches_attributes, &mut leaves_attributes)?;
+        },
+        GenerationAlgorithm::LSystem => {
+            let (full_skeleton, leaves) = lsystem::generate_skeleton(&settings.lsystem, settings.branch.trunk_base_radius, rng)?;
+            let (skeleton, aged_indices) = age_skeleton(&full_skeleton, context.age);
+            generate_from_skeleton(settings, context, &skeleton, bark_seed, rng, &mut branches_attributes, &mut leaves_attributes)?;
+            let node_times = skeleton_times(&skeleton);
 
-    recurse_a_branch(settings, state, rng, &mut branches_attributes, &mut leaves_attributes)?;
+            // catch going outside of the allowed range early and tell the user
+            let approx_amount_of_indices_of_the_leaves: usize = leaves.len() * settings.leaves.shape.outline().len().max(NEEDLE_STRIPS_PER_CLUSTER * 2) * 6;
+            #[cfg(not(feature = "u32_indices"))]
+            if leaves_attributes.indices.len() >= (u16::MAX as usize).saturating_sub(approx_amount_of_indices_of_the_leaves) {
+                return Err(IndicesOverflowError.into());
+            }
+            #[cfg(feature = "u32_indices")]
+            if leaves_attributes.indices.len() >= (u32::MAX as usize).saturating_sub(approx_amount_of_indices_of_the_leaves) {
+                return Err(IndicesOverflowError.into());
+            }
+            for (origin, orientation, node) in leaves.into_iter().filter(|(origin, _, _)| distance_to_obstacles(&context.obstacles, *origin) >= 0.0) {
+                // leaves grow with the node they are attached to
+                let (aged_node, growth) = match node {
+                    Some(node) => match aged_indices[node] {
+                        Some(aged_node) => aged_node,
+                        None => continue,
+                    },
+                    None => (usize::MAX, context.age.max(0.0)),
+                };
+                let node_position = full_skeleton.get(node.unwrap_or(usize::MAX)).map_or(origin, |node| node.position);
+                let aged_position = skeleton.get(aged_node).map_or(origin, |node| node.position);
+                let first_leaf = leaves_attributes.first_new();
+                generate_leaf(settings, origin, orientation, rng, &mut leaves_attributes)?;
+                age_leaves(context, &mut leaves_attributes, first_leaf, |point| aged_position + (point - node_position) * growth, growth);
+                leaves_attributes.set_growth_time(leaf_unfold_time(node_times.get(aged_node).copied().unwrap_or(0.0)));
+            }
+        },
+    }
+
+    for root_state in generate_roots(settings, bark_seed, rng) {
+        recurse_a_branch(settings, context, root_state, rng, &mut shade_grid, &mut branches_attributes, &mut leaves_attributes)?;
+    }
+
+    // crown based normals need all leaves to be generated
+    apply_crown_leaf_normals(settings, &mut leaves_attributes);
+    // so do the ambient occlusion and the height gradient of the vertex colors
+    let ambient_occlusion = settings.ambient_occlusion.enabled.then(|| {
+        let surfaces = [&branches_attributes, &leaves_attributes].map(|attributes| OcclusionSurface {
+            positions: &attributes.positions,
+            normals: &attributes.normals,
+            indices: &attributes.indices,
+        });
+        let mut occlusion = bake_ambient_occlusion(&settings.ambient_occlusion, &surfaces);
+        let leaves_occlusion = occlusion.pop().unwrap_or_default();
+        (occlusion.pop().unwrap_or_default(), leaves_occlusion)
+    });
+    let vertex_colors = settings.vertex_colors.enabled.then(|| vertex_colors(settings, ambient_occlusion.as_ref(), &mut branches_attributes, &leaves_attributes));
+    let surface_masks = settings.surface_masks.enabled.then(|| surface_masks(settings, &branches_attributes, &leaves_attributes));
     
     // build meshes
     let mut branches_mesh = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD);
     branches_mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, branches_attributes.positions);
     branches_mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, branches_attributes.normals);
     branches_mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, branches_attributes.uvs);
+    if context.growth_animation {
+        branches_mesh.insert_attribute(ATTRIBUTE_GROWTH_PIVOT, branches_attributes.growth_pivots);
+        branches_mesh.insert_attribute(ATTRIBUTE_GROWTH_TIME, branches_attributes.growth_times);
+    }
     #[cfg(not(feature = "u32_indices"))]
     branches_mesh.insert_indices(Indices::U16(branches_attributes.indices));
     #[cfg(feature = "u32_indices")]
     branches_mesh.insert_indices(Indices::U32(branches_attributes.indices));
-    //branches_mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, branches_colors);
-    branches_mesh.generate_tangents()?;
+    // i.e. trees at the age of 0.0 (tangents can not be generated for empty meshes)
+    if branches_mesh.count_vertices() > 0 {
+        branches_mesh.generate_tangents()?;
+    }
 
     let mut leaves_mesh = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD);
     leaves_mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, leaves_attributes.positions);
     leaves_mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, leaves_attributes.normals);
     leaves_mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, leaves_attributes.uvs);
+    if !leaves_attributes.leaf_centers.is_empty() {
+        leaves_mesh.insert_attribute(ATTRIBUTE_LEAF_CENTER, leaves_attributes.leaf_centers);
+        leaves_mesh.insert_attribute(ATTRIBUTE_LEAF_CORNER, leaves_attributes.leaf_corners);
+    }
+    if let Some((branches_colors, leaves_colors)) = vertex_colors {
+        branches_mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, branches_colors);
+        leaves_mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, leaves_colors);
+    }
+    if let Some((branches_occlusion, leaves_occlusion)) = ambient_occlusion {
+        branches_mesh.insert_attribute(ATTRIBUTE_AMBIENT_OCCLUSION, branches_occlusion);
+        leaves_mesh.insert_attribute(ATTRIBUTE_AMBIENT_OCCLUSION, leaves_occlusion);
+    }
+    if let Some((branches_masks, leaves_masks)) = surface_masks {
+        branches_mesh.insert_attribute(ATTRIBUTE_SURFACE_MASKS, branches_masks);
+        leaves_mesh.insert_attribute(ATTRIBUTE_SURFACE_MASKS, leaves_masks);
+    }
+    if context.seasons {
+        leaves_mesh.insert_attribute(ATTRIBUTE_LEAF_PIVOT, leaves_attributes.growth_pivots.clone());
+        leaves_mesh.insert_attribute(ATTRIBUTE_LEAF_RANDOM, leaves_attributes.leaf_randoms);
+    }
+    if context.growth_animation {
+        leaves_mesh.insert_attribute(ATTRIBUTE_GROWTH_PIVOT, leaves_attributes.growth_pivots);
+        leaves_mesh.insert_attribute(ATTRIBUTE_GROWTH_TIME, leaves_attributes.growth_times);
+    }
     #[cfg(not(feature = "u32_indices"))]
     leaves_mesh.insert_indices(Indices::U16(leaves_attributes.indices));
     #[cfg(feature = "u32_indices")]
     leaves_mesh.insert_indices(Indices::U32(leaves_attributes.indices));
-    leaves_mesh.generate_tangents()?;
+    // i.e. L-systems without leaves or young trees
+    if leaves_mesh.count_vertices() > 0 {
+        leaves_mesh.generate_tangents()?;
+    }
 
     Ok((branches_mesh, leaves_mesh))
 }
@@ -105,17 +567,14 @@ fn generate_branches_internal(settings: &TreeMeshSettings, state: BranchGenState
 #[allow(clippy::too_many_arguments)]
 fn recurse_a_branch(
     settings: &TreeMeshSettings,
+    context: &TreeGenerationContext,
     state: BranchGenState,
     rng: &mut Rng,
+    shade_grid: &mut ShadeGrid,
     branches_attributes: &mut MeshAttributes,
-    //branches_colors: &mut Vec<[f32; 4]>,
     leaves_attributes: &mut MeshAttributes
 ) -> Result<(), BevyError>
 {       
-    #[cfg(not(feature = "u32_indices"))]
-    let indices_start: u16 = branches_attributes.positions.len() as u16;
-    #[cfg(feature = "u32_indices")]
-    let indices_start: u32 = branches_attributes.positions.len() as u32;
     // catch going outside of the allowed range early and tell the user
     let approx_amount_of_indices_of_this_branch: usize = state.sections * state.segments * 6;
     #[cfg(not(feature = "u32_indices"))]
@@ -139,7 +598,7 @@ fn recurse_a_branch(
     let factor_for_length: f32 = if state.level > 0 {1.0} else {
         match settings.tree_type {
             TreeType::Deciduous => (settings.branch.levels as usize - state.recursion_count + 1) as f32 / target_pieces,
-            TreeType::Evergreen => 1.0,
+            TreeType::Evergreen | TreeType::Palm | TreeType::Shrub => 1.0,
         }
     };
 
@@ -163,76 +622,48 @@ fn recurse_a_branch(
     // for Evergreen we need 'sections' steps, so that at the top we have the target taper
     // for Deciduous we need even more steps, due to the trunk being build from sections*levels parts
     let taper_amount_per_section = match settings.tree_type {
+        _ if state.is_root => f32::powf(1.0 - state.taper.clamp(0.0, 0.9999), 1.0/state.sections as f32),
         TreeType::Deciduous => f32::powf(1.0 - state.taper.clamp(0.0, 0.9999), (1.0/state.sections as f32) / (f32::from(settings.branch.levels) + 1.0)),
-        TreeType::Evergreen => f32::powf(1.0 - state.taper.clamp(0.0, 0.9999), 1.0/state.sections as f32), 
+        TreeType::Evergreen | TreeType::Palm | TreeType::Shrub => f32::powf(1.0 - state.taper.clamp(0.0, 0.9999), 1.0/state.sections as f32), 
     };
     
+    // set if the trunk splits into co-dominant stems (the sections end early)
+    let mut forked = false;
+    // set if the branch stopped at an obstacle (the sections end early)
+    let mut blocked = false;
+
+    // palm trunks bend in a random direction
+    let is_palm_trunk = settings.tree_type == TreeType::Palm && state.level == 0 && !state.is_root;
+    let palm_bend = if is_palm_trunk {
+        let bend_direction = rng.f32() * 2.0 * PI;
+        Quat::from_axis_angle(Vec3::new(bend_direction.cos(), 0.0, bend_direction.sin()), settings.palm.bend.to_radians() / state.sections as f32)
+    } else {
+        Quat::IDENTITY
+    };
+
     // iterate over sections + one final ring
     // the =sections is needed because to have x sections, we need x+1 rows of vertices
-    for section_counter in 0..=state.sections {
+    for section_counter in state.first_section..=state.sections {
         // update radius
-        if section_counter == state.sections && !((state.level == 0) && matches!(settings.tree_type, TreeType::Deciduous)) {
-            // last ring of the last section is a tip (except the main branch/trunk of deciduous trees)
+        if section_counter == state.sections && !((state.level == 0) && matches!(settings.tree_type, TreeType::Deciduous | TreeType::Palm)) {
+            // last ring of the last section is a tip (except the main branch/trunk of deciduous trees and palms)
             section_radius = f32::EPSILON;
         } 
     
-        // save the first vertex to create a ring in the end
-        let mut first_pos = Vec3::ZERO;
-        let mut first_nrm = Vec3::ZERO;
-        let mut first_v  = 0.0;
-    
-        // for each segment create a single vertex
-        for segment_counter in 0..state.segments {
-            let angle = (2.0 * PI * segment_counter as f32) / state.segments as f32;
-            let (sin, cos) = angle.sin_cos();    
-            
-            let local_pos = Vec3::new(cos * section_radius, 0.0, sin * section_radius);
-            let local_normal = Vec3::new(cos, 0.0, sin);
-    
-            let vertex = (section_orientation * local_pos) + section_origin;
-            let normal = (section_orientation * local_normal).normalize();
-    
-            let u = segment_counter as f32 / state.segments as f32;
-            let v = if section_counter % 2 == 0 { 0.0 } else { 1.0 };
-        
-            if segment_counter == 0 {
-                first_pos = vertex;
-                first_nrm = normal;
-                first_v = v;
-            }            
-            branches_attributes.positions.push(vertex.to_array());
-            branches_attributes.normals.push(normal.to_array());
-            branches_attributes.uvs.push([u,v]);
-            // color code levels for debugging
-            // match BranchRecursionLevel::try_from(state.recursion_count as u8).unwrap() {
-            //     BranchRecursionLevel::Zero => branches_colors.push([1.0, 0.0, 0.0, 1.0]),
-            //     BranchRecursionLevel::One => branches_colors.push([0.0, 1.0, 0.0, 1.0]),
-            //     BranchRecursionLevel::Two => branches_colors.push([0.0, 0.0, 1.0, 1.0]),
-            //     BranchRecursionLevel::Three => branches_colors.push([0.0, 1.0, 1.0, 1.0]),
-            //     //BranchRecursionLevel::Four => colors.push([1.0, 1.0, 1.0, 1.0]),
-            // }
-            
-        } // END for each segment
-    
-        // duplicate of the first vertex to create a full ring (with different uv)
-        branches_attributes.positions.push(first_pos.to_array());
-        branches_attributes.normals.push(first_nrm.to_array());
-        branches_attributes.uvs.push([1.0, first_v]);
-        // color code levels for debugging
-        // match BranchRecursionLevel::try_from(state.recursion_count as u8).unwrap() {
-        //     BranchRecursionLevel::Zero => branches_colors.push([1.0, 0.0, 0.0, 1.0]),
-        //     BranchRecursionLevel::One => branches_colors.push([0.0, 1.0, 0.0, 1.0]),
-        //     BranchRecursionLevel::Two => branches_colors.push([0.0, 0.0, 1.0, 1.0]),
-        //     BranchRecursionLevel::Three => branches_colors.push([0.0, 1.0, 1.0, 1.0]),
-        //     //BranchRecursionLevel::Four => colors.push([1.0, 1.0, 1.0, 1.0]),
-        // }
-    
         // save section data for later allow branches to grow from them
         sections.push(SectionData {
             origin: section_origin,
             orientation: section_orientation,
             radius: section_radius
         });
+
+        // the trunk may split into co-dominant stems here
+        if state.level == 0 && !state.is_root && state.fork_depth < settings.trunk.max_forks
+            && (state.first_section + 1..state.sections).contains(&section_counter)
+            && settings.trunk.fork_probability > 0.0 && rng.f32() < settings.trunk.fork_probability {
+            forked = true;
+            break;
+        }
     
         //
         // Update section parameters for next section
@@ -250,52 +681,130 @@ fn recurse_a_branch(
             // apply gnarl and twist
             section_orientation = (q_gnarl * section_orientation) * q_twist;
 
-            // slerp the target orientation in the direction of the branch.force based on the given strength and radius of the branch
-            let radius_factor = 1.0 - (section_radius / settings.branch.force.radius_cutoff).clamp(0.0, 1.0);
-            let strength_per_radius = (settings.branch.force.strength * radius_factor / 2.0).clamp(0.0, 1.0); // 2 chosen by trial and error to look natural (values between 0..1 make the most sense now; larger is still possible)
-            section_orientation = section_orientation.slerp(branch_force_quat, strength_per_radius);
+            // bend of palm trunks
+            section_orientation = palm_bend * section_orientation;
+
+            // bend towards the light
+            let phototropism = settings.phototropism.strength[state.level.min(3)];
+            if phototropism > 0.0 && !state.is_root {
+                let light = settings.phototropism.direction.normalize_or(Vec3::Y);
+                let towards_light = Quat::from_rotation_arc(section_orientation * Vec3::Y, light);
+                section_orientation = Quat::IDENTITY.slerp(towards_light, (phototropism * PHOTOTROPISM_PER_SECTION).clamp(0.0, 1.0)) * section_orientation;
+            }
+
+            // droop under the load of the remaining branch
+            if !state.is_root {
+                section_orientation = apply_gravity(settings, &state, section_orientation, section_radius, section_length, section_counter);
+            }
+
+            if state.is_root {
+                // roots slowly dive into the ground
+                let dive = Quat::from_rotation_arc(section_orientation * Vec3::Y, Vec3::NEG_Y);
+                section_orientation = Quat::IDENTITY.slerp(dive, ROOT_DIVE_PER_SECTION) * section_orientation;
+            } else {
+                // slerp the target orientation in the direction of the branch.force based on the given strength and radius of the branch
+                let radius_factor = 1.0 - (section_radius / settings.branch.force.radius_cutoff).clamp(0.0, 1.0);
+                let strength_per_radius = (settings.branch.force.strength * radius_factor / 2.0).clamp(0.0, 1.0); // 2 chosen by trial and error to look natural (values between 0..1 make the most sense now; larger is still possible)
+                section_orientation = section_orientation.slerp(branch_force_quat, strength_per_radius);
+            }
 
             // taper
             section_radius *= taper_amount_per_section;
 
+            // bend away from obstacles in front of the branch; stop if the next section would be inside of an obstacle
+            if !context.obstacles.is_empty() {
+                section_orientation = avoid_obstacles(settings, context, section_orientation, section_origin + section_orientation * Vec3::Y * section_length, section_radius);
+                let next_origin = section_origin + section_orientation * Vec3::Y * section_length;
+                if settings.obstacles.stop_inside && distance_to_obstacles(&context.obstacles, next_origin) < section_radius {
+                    blocked = true;
+                    break;
+                }
+            }
+
             // direction (go along the branch)
             let up = section_orientation * Vec3::Y;
             section_origin += up * section_length;
         }        
     } // END for each section
-    
-    // Indices (triangles) are build around the ring per segment
-    #[cfg(not(feature = "u32_indices"))]
-    {
-        let ring_stride: u16 = state.segments as u16 + 1;    
-        for i in 0..state.sections as u16 {
-            for j in 0..state.segments as u16 {
-                let a: u16 = i * ring_stride        + j         + indices_start;
-                let b: u16 = i * ring_stride        + (j + 1)   + indices_start;
-                let c: u16 = a + ring_stride;
-                let d: u16 = b + ring_stride;
-        
-                branches_attributes.indices.extend_from_slice(&[a, c, b, b, c, d]);
-            }
+
+    // the branch at the age of the tree: scaled around its (aged) origin while growing and thinner while the tree is young
+    // (the skeleton and the use of the rng are the same for all ages, so the tree is stable while growing)
+    let span = growth_span(settings, &state);
+    let growth = growth_factor(context.age, state.birth, span);
+    let aged = |point: Vec3| if context.age < 1.0 { state.aged_origin + (point - state.origin) * growth } else { point };
+    // deciduous trunks are continued by the next part of the trunk once it starts to grow (until then the trunk ends in a tip)
+    let is_trunk_part = !blocked && matches!(settings.tree_type, TreeType::Deciduous) && state.level == 0 && state.recursion_count < settings.branch.levels as usize;
+    let continuation_grows = growth_factor(context.age, state.birth + span, span) > 0.0;
+    let aged_branch = (context.age < 1.0).then(|| {
+        let (aged_state, mut aged_sections) = age_branch(&state, &sections, aged, growth_factor(context.age, state.birth, 1.0 - state.birth));
+        if let Some(last_section) = aged_sections.last_mut().filter(|_| is_trunk_part && !continuation_grows && !forked) {
+            last_section.radius = f32::EPSILON;
         }
-    }  
-    #[cfg(feature = "u32_indices")]
-    {
-        let ring_stride: u32 = state.segments as u32 + 1;    
-        for i in 0..state.sections as u32 {
-            for j in 0..state.segments as u32 {
-                let a: u32 = i * ring_stride        + j         + indices_start;
-                let b: u32 = i * ring_stride        + (j + 1)   + indices_start;
-                let c: u32 = a + ring_stride;
-                let d: u32 = b + ring_stride;
-        
-                branches_attributes.indices.extend_from_slice(&[a, c, b, b, c, d]);
-            }
+        (aged_state, aged_sections)
+    });
+    let (mesh_state, mesh_sections) = aged_branch.as_ref().map_or((&state, &sections[..]), |(aged_state, aged_sections)| (aged_state, &aged_sections[..]));
+
+    // the growth animation extends the branch ring by ring (children start when the branch has grown up to them)
+    let growth_times: Vec<f32> = (0..sections.len())
+        .map(|i| branch_growth_time(state.birth + span * (state.first_section + i) as f32 / state.sections as f32))
+        .collect();
+    let leaf_time = if is_palm_trunk {
+        // fronds unfold while the trunk grows
+        [branch_growth_time(state.birth), 1.0]
+    } else {
+        leaf_unfold_time(branch_growth_time(state.birth + span))
+    };
+
+    let (first_branch_vertex, first_branch_index) = branches_attributes.first_new();
+    let branch_end_v = build_branch_mesh(settings, mesh_state, mesh_sections, &growth_times, branches_attributes);
+    let last_ring = branches_attributes.positions.len().checked_sub(state.segments + 1);
+
+    // close the end of the branch if it does not end in a tip and is not continued by another part of the trunk
+    // (forks always get a flat cap: the thinner, diverging stems do not cover the last ring of the trunk)
+    let is_continued = !forked && is_trunk_part && continuation_grows;
+    let end_cap = if forked { EndCap::Flat } else { settings.branch.end_cap };
+    if let Some(last_section) = mesh_sections.last().filter(|section| !is_continued && section.radius > f32::EPSILON) {
+        build_end_cap(settings, end_cap, last_section, state.segments, rng, branches_attributes);
+        branches_attributes.extend_growth();
+    }
+    // roots are colored like the trunk they grow from
+    branches_attributes.set_level(if state.is_root { 0 } else { state.level });
+
+    // branches which did not start to grow yet are only generated to keep the rng stable
+    if growth <= 0.0 {
+        branches_attributes.truncate(first_branch_vertex, first_branch_index);
+    }
+
+    if state.is_root {
+        return Ok(());
+    }
+
+    // the shade of this branch on the branches generated after it (from the full grown sections, so that the shape of the tree does not depend on its age)
+    if grows_in_shade(settings) {
+        shade_grid.add_tube(&sections.iter().map(|section| (section.origin, section.radius)).collect::<Vec<_>>());
+    }
+
+    if forked {
+        // the stems continue the remaining sections of this part of the trunk
+        let fork_section = state.first_section + sections.len() - 1;
+        for stem in generate_fork_stems(settings, &state, section_origin, section_orientation, section_radius, fork_section, branch_end_v, rng) {
+            let stem = BranchGenState { aged_origin: aged(stem.origin), ..stem };
+            recurse_a_branch(settings, context, stem, rng, shade_grid, branches_attributes, leaves_attributes)?;
         }
-    }  
+    }
 
+    if is_palm_trunk {
+        // palms are unbranched: only a crown of fronds at the top
+        if !forked {
+            let first_leaf = leaves_attributes.first_new();
+            generate_fronds(settings, section_origin, section_orientation, rng, leaves_attributes)?;
+            age_leaves(context, leaves_attributes, first_leaf, aged, growth);
+            leaves_attributes.set_growth_time(leaf_time);
+        }
+        return Ok(());
+    }
 
-    if matches!(settings.tree_type, TreeType::Deciduous) && state.level == 0 {
+    if matches!(settings.tree_type, TreeType::Deciduous) && state.level == 0 && !forked && !blocked {
         if state.recursion_count < settings.branch.levels as usize {
             // Deciduous trunks are build itnernally from multiple continous branches (for nicer branch generation)
             let additional_trunk_part = BranchGenState {
@@ -312,29 +821,59 @@ fn recurse_a_branch(
                 // since the child branch is growing from the end of the parent branch           
                 sections: state.sections,
                 segments: state.segments,
+                // continue the bark texture of the parent part
+                v_start: branch_end_v,
+                parent_joint: None,
+                is_root: false,
+                bark_seed: state.bark_seed,
+                fork_depth: state.fork_depth,
+                first_section: 0,
+                first_ring: state.first_ring + state.first_section + sections.len() - 1,
+                noise_phases: state.noise_phases,
+                // the next part starts growing when this part is fully grown
+                birth: state.birth + span,
+                aged_origin: aged(section_origin),
+                seam_ring: last_ring.filter(|_| growth > 0.0),
             };
-            recurse_a_branch(settings, additional_trunk_part, rng, branches_attributes, leaves_attributes)?;
+            recurse_a_branch(settings, context, additional_trunk_part, rng, shade_grid, branches_attributes, leaves_attributes)?;
         }
         else {
             // generate a nice single leaf at the top
+            let first_leaf = leaves_attributes.first_new();
             generate_leaf(settings, section_origin, section_orientation, rng, leaves_attributes)?;
+            age_leaves(context, leaves_attributes, first_leaf, aged, growth);
+            leaves_attributes.set_growth_time(leaf_time);
         }
     }
 
     if state.recursion_count == settings.branch.levels as usize {
         // generate leaves at the different sections of this branch
         // state.level is constant in this case, we keep it as a parameter for possible future functionality
-        generate_leaves(&sections, settings, rng, leaves_attributes)?;
+        let first_leaf = leaves_attributes.first_new();
+        generate_leaves(&sections, settings, context, rng, leaves_attributes)?;
+        if grows_in_shade(settings) {
+            shade_grid.add_triangles(&OcclusionSurface {
+                positions: &leaves_attributes.positions,
+                normals: &leaves_attributes.normals,
+                indices: &leaves_attributes.indices[first_leaf.1..],
+            });
+        }
+        age_leaves(context, leaves_attributes, first_leaf, aged, growth);
+        leaves_attributes.set_growth_time(leaf_time);
     }
     else {
         for child_branch_state in generate_child_branches(
             settings.branch.children[state.recursion_count],
             state.recursion_count + 1,
             &sections,
+            &state,
             settings,
+            context,
+            shade_grid,
             rng
         ) {
-            recurse_a_branch(settings, child_branch_state, rng, branches_attributes, leaves_attributes)?;
+            let child_branch_state = BranchGenState { aged_origin: aged(child_branch_state.origin), ..child_branch_state };
+            recurse_a_branch(settings, context, child_branch_state, rng, shade_grid, branches_attributes, leaves_attributes)?;
         }
     }
 
@@ -342,106 +881,1020 @@ fn recurse_a_branch(
 }
 
 
-
-fn generate_child_branches (
-    count: u8,
-    level: usize,
-    parent_sections: &[SectionData],
+/// Build the branches and leaves of a skeleton (see GenerationAlgorithm)
+/// 
+/// Each chain of nodes (following the thickest child) becomes a branch; the other children start new branches one level higher.
+/// Space colonization branches end in a tip and get leaves along their length (L-systems place their own leaves and may end with end caps).
+#[allow(clippy::too_many_arguments)]
+fn generate_from_skeleton(
     settings: &TreeMeshSettings,
+    context: &TreeGenerationContext,
+    nodes: &[SkeletonNode],
+    bark_seed: u32,
     rng: &mut Rng,
-) -> Vec<BranchGenState> {
-    if count == 0 || parent_sections.is_empty(){
-        return Vec::new();
+    branches_attributes: &mut MeshAttributes,
+    leaves_attributes: &mut MeshAttributes
+) -> Result<(), BevyError>
+{
+    if nodes.is_empty() {
+        return Ok(());
     }
 
-    let radial_offset: f32 = rng.f32(); 
-    let section_count_minus_one: usize = parent_sections.len().saturating_sub(1);  
+    let is_space_colonization = settings.algorithm == GenerationAlgorithm::SpaceColonization;
+    let node_times = skeleton_times(nodes);
 
-    let mut out = Vec::with_capacity(count as usize);
-    for i in 0..count {
-        // lowest start position along the parent branch as a factor
-        let child_start_factor = f32::lerp(settings.branch.start[level].clamp(0.0, 1.0), 1.0, rng.f32());
+    // children of each node (lowest level first, then thickest first)
+    let mut children: Vec<Vec<usize>> = vec![Vec::new(); nodes.len()];
+    for (index, node) in nodes.iter().enumerate() {
+        if let Some(parent) = node.parent {
+            children[parent].push(index);
+        }
+    }
+    for node_children in children.iter_mut() {
+        node_children.sort_by(|a, b| nodes[*a].level.cmp(&nodes[*b].level).then(nodes[*b].radius.total_cmp(&nodes[*a].radius)));
+    }
 
-        // calculate a factor between two sections based on the possible range
-        let child_branch_pos = child_start_factor * section_count_minus_one as f32;
-        let section_index = child_branch_pos.floor() as usize;
-        let branch_height_factor = (child_branch_pos - section_index as f32).clamp(0.0, 1.0);
+    // axis of the skeleton at a node (from its parent to the node)
+    let axis = |index: usize| nodes[index].parent.map_or(Vec3::Y, |parent| (nodes[index].position - nodes[parent].position).normalize_or(Vec3::Y));
 
-        // calculate target sections where to place the branch
-        let section_a_index = section_index; 
-        let section_b_index = (section_index + 1).min(section_count_minus_one);
-        let section_a = &parent_sections[section_a_index];
-        let section_b = &parent_sections[section_b_index];
+    // branches to build: first node, node of the parent branch it grows from and level
+    let mut pending: Vec<(usize, Option<usize>, usize)> = nodes.iter().enumerate()
+        .filter(|(_, node)| node.parent.is_none())
+        .map(|(index, _)| (index, None, 0))
+        .collect();
+    while let Some((first_node, parent_node, level)) = pending.pop() {
+        let mut chain: Vec<usize> = parent_node.into_iter().chain([first_node]).collect();
+        let mut current = first_node;
+        while let Some((&next, side_branches)) = children[current].split_first() {
+            pending.extend(side_branches.iter().map(|side_branch| (*side_branch, Some(current), level + 1)));
+            chain.push(next);
+            current = next;
+        }
+        if chain.len() < 2 {
+            continue;
+        }
 
-        // interpolate the placement between section a and b
-        let child_branch_origin = section_a.origin.lerp(section_b.origin, branch_height_factor);
+        let settings_level = level.min(3);
+        let segments = settings.branch.segments[settings_level] as usize;
 
-        // calculate radius
-        // TODO is this correct?
-        let radius_setting = settings.branch.radius_factor[level];
-        let parent_radius = f32::lerp(section_a.radius, section_b.radius, branch_height_factor);
-        let child_branch_radius = radius_setting * parent_radius;
+        // catch going outside of the allowed range early and tell the user
+        let approx_amount_of_indices_of_this_branch: usize = chain.len() * segments * 6;
+        #[cfg(not(feature = "u32_indices"))]
+        if branches_attributes.indices.len() >= (u16::MAX as usize - approx_amount_of_indices_of_this_branch) {
+            return Err(IndicesOverflowError.into());
+        }
+        #[cfg(feature = "u32_indices")]
+        if branches_attributes.indices.len() >= (u32::MAX as usize - approx_amount_of_indices_of_this_branch) {
+            return Err(IndicesOverflowError.into());
+        }
 
-        // orient along the parent sections
-        let parent_orientation = section_b.orientation.slerp(section_a.orientation, branch_height_factor);
+        // the orientation is carried along the branch (no twisting of the rings between the nodes)
+        let last = chain.len() - 1;
+        let mut orientation = Quat::from_rotation_arc(Vec3::Y, (nodes[chain[1]].position - nodes[chain[0]].position).normalize_or(Vec3::Y));
+        let sections: Vec<SectionData> = chain.iter().enumerate().map(|(i, node)| {
+            let previous = nodes[chain[i.saturating_sub(1)]].position;
+            let next = nodes[chain[(i + 1).min(last)]].position;
+            let direction = (next - previous).normalize_or(orientation * Vec3::Y);
+            orientation = Quat::from_rotation_arc(orientation * Vec3::Y, direction) * orientation;
+            let radius = match i {
+                // side branches start with their own radius at the axis of the parent branch
+                0 if parent_node.is_some() => nodes[first_node].radius,
+                // all branches of space colonization end in a tip
+                _ if i == last && is_space_colonization => f32::EPSILON,
+                _ => nodes[*node].radius,
+            };
+            SectionData { origin: nodes[*node].position, orientation, radius }
+        }).collect();
 
-        // calculate needed angles 
-        let radial_angle = 2.0 * std::f32::consts::PI * (radial_offset + (i as f32) / (count as f32));
-        let angle_rad = settings.branch.angle[level].to_radians();
-        let q1 = Quat::from_axis_angle(Vec3::X, angle_rad);
-        let q2 = Quat::from_axis_angle(Vec3::Y, radial_angle);
-        let child_quat = parent_orientation * q2 * q1;
+        let state = BranchGenState {
+            origin: sections[0].origin,
+            orientation: sections[0].orientation,
+            length: sections.windows(2).map(|w| w[0].origin.distance(w[1].origin)).sum(),
+            start_radius: sections[0].radius,
+            taper: 0.0,
+            twist: 0.0,
+            gnarliness: 0.0,
+            level: settings_level,
+            recursion_count: settings_level,
+            sections: last,
+            segments,
+            v_start: 0.0,
+            parent_joint: parent_node.map(|parent_node| ParentJoint {
+                origin: nodes[parent_node].position,
+                axis: axis(parent_node),
+                radius: nodes[parent_node].radius,
+            }),
+            is_root: false,
+            bark_seed,
+            fork_depth: 0,
+            first_section: 0,
+            first_ring: 0,
+            noise_phases: noise_phases(settings, settings_level, rng),
+            birth: 0.0,
+            aged_origin: sections[0].origin,
+            seam_ring: None,
+        };
+        let growth_times: Vec<f32> = chain.iter().map(|node| branch_growth_time(node_times[*node])).collect();
+        build_branch_mesh(settings, &state, &sections, &growth_times, branches_attributes);
 
-        // target length
-        let mut child_len = settings.branch.length[level];
-        if settings.tree_type == TreeType::Evergreen {
-            child_len *= 1.0 - child_start_factor;
+        if let Some(last_section) = sections.last().filter(|section| section.radius > f32::EPSILON) {
+            build_end_cap(settings, settings.branch.end_cap, last_section, segments, rng, branches_attributes);
+            branches_attributes.extend_growth();
         }
+        branches_attributes.set_level(settings_level);
 
-        out.push(BranchGenState {
-            origin: child_branch_origin,
-            orientation: child_quat,
-            length: child_len,
-            start_radius: child_branch_radius,
-            level,
-            recursion_count: level,
-            taper: settings.branch.taper[level],
-            twist: settings.branch.twist[level],
-            gnarliness: settings.branch.gnarliness[level],
-            sections: settings.branch.sections[level].into(),
-            segments: settings.branch.segments[level].into()
-        });
+        if level > 0 && is_space_colonization {
+            generate_leaves(&sections, settings, context, rng, leaves_attributes)?;
+            leaves_attributes.set_growth_time(leaf_unfold_time(growth_times[last]));
+        }
     }
 
-    out
+    Ok(())
 }
 
-fn generate_leaves(
-    sections: &[SectionData],
-    settings: &TreeMeshSettings,
-    rng: &mut Rng,
-    leaves_attributes: &mut MeshAttributes
-) -> Result<(), BevyError>
-{
-    // catch going outside of the allowed range early and tell the user
-    let approx_amount_of_indices_of_this_leaf: usize = settings.leaves.count as usize * 6;
-    #[cfg(not(feature = "u32_indices"))]
-    if leaves_attributes.indices.len() >= (u16::MAX as usize - approx_amount_of_indices_of_this_leaf) {
-        return Err(IndicesOverflowError.into());
-    }
-    #[cfg(feature = "u32_indices")]
-    if leaves_attributes.indices.len() >= (u32::MAX as usize - approx_amount_of_indices_of_this_leaf) {
-        return Err(IndicesOverflowError.into());
+/// How long a branch grows (as a part of the age of the tree)
+/// 
+/// Each level of recursion gets the same time, so all branches are fully grown at the age of 1.0 (roots and palm trunks grow over the whole lifetime).
+fn growth_span(settings: &TreeMeshSettings, state: &BranchGenState) -> f32 {
+    if state.is_root || settings.tree_type == TreeType::Palm {
+        1.0
+    } else {
+        1.0 / (f32::from(settings.branch.levels) + 1.0)
     }
+}
 
-    let radial_offset: f32 = rng.f32();
-    let section_count_minus_one: usize = sections.len().saturating_sub(1);  
+/// How far something which starts at `start` and grows for `span` is grown at the given age (0..1; always 1 for fully grown trees)
+fn growth_factor(age: f32, start: f32, span: f32) -> f32 {
+    if age >= 1.0 {
+        1.0
+    } else {
+        ((age - start) / span.max(f32::EPSILON)).clamp(0.0, 1.0)
+    }
+}
 
-    for i in 0..settings.leaves.count {
-        // how far along the section should this leaf start
-        let leaf_start = f32::lerp(settings.leaves.start.clamp(0.0, 1.0), 1.0, rng.f32());
+/// The sections (and the joint to the parent) of a branch at the age of the tree
+fn age_branch(state: &BranchGenState, sections: &[SectionData], aged: impl Fn(Vec3) -> Vec3, maturity: f32) -> (BranchGenState, Vec<SectionData>) {
+    let aged_sections = sections.iter().map(|section| SectionData {
+        origin: aged(section.origin),
+        orientation: section.orientation,
+        radius: section.radius * maturity,
+    }).collect();
+    let aged_state = BranchGenState {
+        parent_joint: state.parent_joint.as_ref().map(|parent_joint| ParentJoint {
+            origin: aged(parent_joint.origin),
+            axis: parent_joint.axis,
+            radius: parent_joint.radius * maturity,
+        }),
+        ..state.clone()
+    };
+    (aged_state, aged_sections)
+}
 
-        // find relevant sections depending on leaf_start
+/// Move the leaves generated since `first_leaf` to the age of the tree: scaled with their branch (removed if it did not start to grow yet)
+fn age_leaves(context: &TreeGenerationContext, leaves_attributes: &mut MeshAttributes, first_leaf: (usize, usize), aged: impl Fn(Vec3) -> Vec3, growth: f32) {
+    if context.age >= 1.0 {
+        return;
+    }
+    if growth <= 0.0 {
+        leaves_attributes.truncate(first_leaf.0, first_leaf.1);
+        return;
+    }
+    for position in leaves_attributes.positions[first_leaf.0..].iter_mut().chain(leaves_attributes.growth_pivots[first_leaf.0..].iter_mut()) {
+        *position = aged(Vec3::from_array(*position)).to_array();
+    }
+    if !leaves_attributes.leaf_centers.is_empty() {
+        for center in leaves_attributes.leaf_centers[first_leaf.0..].iter_mut() {
+            *center = aged(Vec3::from_array(*center)).to_array();
+        }
+        for corner in leaves_attributes.leaf_corners[first_leaf.0..].iter_mut() {
+            *corner = (Vec2::from_array(*corner) * growth).to_array();
+        }
+    }
+}
+
+/// Time (0..1) at which each node of a skeleton is reached while growing: the distance from the base along the branches relative to the longest path
+fn skeleton_times(nodes: &[SkeletonNode]) -> Vec<f32> {
+    // parents always have a lower index
+    let mut distances = vec![0.0; nodes.len()];
+    for (index, node) in nodes.iter().enumerate() {
+        if let Some(parent) = node.parent {
+            distances[index] = distances[parent] + node.position.distance(nodes[parent].position);
+        }
+    }
+    let max_distance = distances.iter().copied().fold(f32::EPSILON, f32::max);
+    distances.into_iter().map(|distance| distance / max_distance).collect()
+}
+
+/// Progress of the growth animation for a time of the branch growth (0..1; leaves some time for the leaves to unfold)
+fn branch_growth_time(time: f32) -> f32 {
+    time.clamp(0.0, 1.0) * (1.0 - LEAF_UNFOLD_TIME)
+}
+
+/// Progress of the growth animation in which leaves unfold, which start on a branch fully grown at the given progress
+fn leaf_unfold_time(branch_end: f32) -> [f32; 2] {
+    [branch_end, (branch_end + LEAF_UNFOLD_TIME).min(1.0)]
+}
+
+/// The skeleton at the age of the tree: the nodes appear in the order of their distance from the base (along the branches) and get thicker over time
+/// 
+/// Returns the visible nodes and for each node its new index and how far it is grown (none if it did not start to grow yet).
+fn age_skeleton(nodes: &[SkeletonNode], age: f32) -> (Vec<SkeletonNode>, Vec<Option<(usize, f32)>>) {
+    if age >= 1.0 {
+        return (nodes.to_vec(), (0..nodes.len()).map(|index| Some((index, 1.0))).collect());
+    }
+
+    let times = skeleton_times(nodes);
+    let mut aged_nodes: Vec<SkeletonNode> = Vec::with_capacity(nodes.len());
+    let mut aged_indices: Vec<Option<(usize, f32)>> = vec![None; nodes.len()];
+    for (index, node) in nodes.iter().enumerate() {
+        let (position, parent, growth) = match node.parent {
+            None => (node.position, None, 1.0),
+            Some(parent) => {
+                let parent_time = times[parent];
+                let Some((aged_parent, _)) = aged_indices[parent].filter(|_| age > parent_time) else {
+                    continue;
+                };
+                let growth = growth_factor(age, parent_time, times[index] - parent_time);
+                (aged_nodes[aged_parent].position + (node.position - nodes[parent].position) * growth, Some(aged_parent), growth)
+            },
+        };
+        aged_indices[index] = Some((aged_nodes.len(), growth));
+        aged_nodes.push(SkeletonNode { position, parent, radius: node.radius * age.max(0.0), level: node.level });
+    }
+
+    (aged_nodes, aged_indices)
+}
+
+/// Bend the orientation of the next section away from obstacles closer than ObstacleParams::margin (sliding along their surface)
+fn avoid_obstacles(settings: &TreeMeshSettings, context: &TreeGenerationContext, orientation: Quat, next_origin: Vec3, radius: f32) -> Quat {
+    let params = &settings.obstacles;
+    let clearance = distance_to_obstacles(&context.obstacles, next_origin) - radius;
+    if clearance >= params.margin {
+        return orientation;
+    }
+    let away = obstacle_normal(&context.obstacles, next_origin);
+    let direction = orientation * Vec3::Y;
+    // remove the part of the direction which points into the obstacle
+    let target = (direction - away * direction.dot(away).min(0.0) + away * AVOIDANCE_PUSH).normalize_or(away);
+    let urgency = (1.0 - clearance / params.margin.max(f32::EPSILON)).clamp(0.0, 1.0);
+    Quat::IDENTITY.slerp(Quat::from_rotation_arc(direction, target), (params.avoidance * urgency).clamp(0.0, 1.0)) * orientation
+}
+
+/// Bend the orientation of the next section down (see GravityParams)
+fn apply_gravity(settings: &TreeMeshSettings, state: &BranchGenState, orientation: Quat, radius: f32, section_length: f32, section_counter: usize) -> Quat {
+    let gravity = &settings.branch.gravity;
+    if gravity.mode == GravityMode::None {
+        return orientation;
+    }
+    let direction = orientation * Vec3::Y;
+    let angle_to_ground = direction.angle_between(Vec3::NEG_Y);
+    if angle_to_ground < f32::EPSILON {
+        return orientation;
+    }
+
+    // load: length of the branch beyond this section; stiffness grows with the radius
+    let flexibility = gravity.flexibility[state.level.min(3)].max(0.0);
+    let progress = (section_counter + 1) as f32 / state.sections as f32;
+    let load = (1.0 - progress).max(0.0) * state.length;
+    let mut bend = gravity.strength * flexibility * load * section_length / (radius.max(f32::EPSILON) * GRAVITY_STIFFNESS);
+
+    // weeping: the tips are pulled towards the ground until they hang vertically
+    if gravity.mode == GravityMode::Weeping && flexibility > 0.0 {
+        let start = gravity.weeping_start.clamp(0.0, 0.99);
+        let weep = ((progress - start) / (1.0 - start)).clamp(0.0, 1.0);
+        bend = bend.max(angle_to_ground * weep);
+    }
+
+    let sag = Quat::from_rotation_arc(direction, Vec3::NEG_Y);
+    Quat::IDENTITY.slerp(sag, (bend / angle_to_ground).clamp(0.0, 1.0)) * orientation
+}
+
+/// radius multiplier (and its derivative) of a lobed cross-section at the given angle of the ring
+fn lobed_profile(angle: f32, lobes: u8, depth: f32) -> (f32, f32) {
+    if lobes == 0 || depth <= 0.0 {
+        return (1.0, 0.0);
+    }
+    // keep the troughs away from the center of the ring
+    let depth = depth.min(0.9);
+    let lobes = lobes as f32;
+    let (sin, cos) = (lobes * angle).sin_cos();
+    (1.0 + depth * cos, -depth * lobes * sin)
+}
+
+/// radius multiplier (and its derivative) of the given cross-section at the given angle of the ring
+/// 
+/// the noise slowly rotates along the branch (with the index of the ring), so neighbouring rings are similar but not identical
+fn cross_section_profile(cross_section: CrossSection, angle: f32, noise_phases: &[f32; NOISE_HARMONICS], ring: usize) -> (f32, f32) {
+    match cross_section {
+        CrossSection::Circle => (1.0, 0.0),
+        CrossSection::Ellipse(ratio) => {
+            // polar form of an ellipse with the half axes a and b (a*b = 1)
+            let ratio = ratio.clamp(0.05, 1.0);
+            let a = 1.0 / ratio.sqrt();
+            let b = ratio.sqrt();
+            let (sin, cos) = angle.sin_cos();
+            let d = (b * cos).powi(2) + (a * sin).powi(2);
+            (a * b / d.sqrt(), -a * b * (a * a - b * b) * sin * cos / d.powf(1.5))
+        },
+        CrossSection::Lobed { lobes, depth } => lobed_profile(angle, lobes, depth.clamp(0.0, 1.0)),
+        CrossSection::Noise { amount, frequency } => {
+            let amount = amount.clamp(0.0, 0.9);
+            let frequency = frequency.max(1) as f32;
+            let mut profile = 0.0;
+            let mut derivative = 0.0;
+            let mut weights = 0.0;
+            for (harmonic, phase) in noise_phases.iter().enumerate() {
+                let k = (harmonic + 1) as f32;
+                let weight = 1.0 / k;
+                let (sin, cos) = (k * frequency * angle + phase + ring as f32 * NOISE_RING_DRIFT * k).sin_cos();
+                profile += weight * sin;
+                derivative += weight * k * frequency * cos;
+                weights += weight;
+            }
+            (1.0 + amount * profile / weights, amount * derivative / weights)
+        },
+    }
+}
+
+/// random phases of the cross-section noise of a branch of the given level (only drawn for noisy cross-sections)
+fn noise_phases(settings: &TreeMeshSettings, level: usize, rng: &mut Rng) -> [f32; NOISE_HARMONICS] {
+    match settings.branch.cross_section[level.min(3)] {
+        CrossSection::Noise { .. } => std::array::from_fn(|_| rng.f32() * 2.0 * PI),
+        _ => [0.0; NOISE_HARMONICS],
+    }
+}
+
+/// All stems growing from the shared base (only the given trunk if TrunkParams::count is 0 or 1)
+fn generate_trunks(settings: &TreeMeshSettings, trunk: BranchGenState, rng: &mut Rng) -> Vec<BranchGenState> {
+    if settings.tree_type == TreeType::Shrub {
+        return generate_shrub_stems(settings, trunk, rng);
+    }
+
+    let params = &settings.trunk;
+    if params.count <= 1 {
+        return vec![trunk];
+    }
+
+    let radial_offset: f32 = rng.f32();
+    let spread = params.spread_angle.to_radians();
+    (0..params.count).map(|i| {
+        let radial_angle = 2.0 * PI * (radial_offset + (i as f32 + (rng.f32() - 0.5) * 0.5) / params.count as f32);
+        let q_radial = Quat::from_axis_angle(Vec3::Y, radial_angle);
+        // the stems lean outwards and stand slightly apart from each other
+        let outward = q_radial * Vec3::Z;
+        let origin = trunk.origin + outward * trunk.start_radius;
+        BranchGenState {
+            origin,
+            aged_origin: origin,
+            orientation: q_radial * Quat::from_axis_angle(Vec3::X, spread * (0.5 + 0.5 * rng.f32())),
+            length: trunk.length * (1.0 - params.length_variance.clamp(0.0, 1.0) * rng.f32()),
+            noise_phases: noise_phases(settings, 0, rng),
+            ..trunk.clone()
+        }
+    }).collect()
+}
+
+/// the settings of the stems of a shrub (see ShrubParams::scale and ShrubParams::levels)
+fn shrub_stem_settings(settings: &TreeMeshSettings) -> TreeMeshSettings {
+    let mut stem_settings = settings.clone();
+    let scale = settings.shrub.scale.max(0.0);
+    stem_settings.branch.levels = if u8::from(settings.shrub.levels) < u8::from(settings.branch.levels) { settings.shrub.levels } else { settings.branch.levels };
+    stem_settings.branch.length = settings.branch.length.map(|length| length * scale);
+    stem_settings.branch.trunk_base_radius *= scale;
+    stem_settings
+}
+
+/// Stems of a shrub: spread over a disc (slightly below the ground), leaning outwards the further they are from the center
+fn generate_shrub_stems(settings: &TreeMeshSettings, trunk: BranchGenState, rng: &mut Rng) -> Vec<BranchGenState> {
+    let params = &settings.shrub;
+    let spread = params.spread_angle.to_radians();
+
+    (0..params.stems).map(|_| {
+        let radial_angle = rng.f32() * 2.0 * PI;
+        // uniform distribution over the disc
+        let distance = rng.f32().sqrt();
+        let q_radial = Quat::from_axis_angle(Vec3::Y, radial_angle);
+        let outward = q_radial * Vec3::Z;
+        let lean = spread * (distance + (rng.f32() - 0.5) * 0.3).clamp(0.0, 1.0);
+        let origin = trunk.origin + outward * distance * params.base_radius - Vec3::Y * params.depth;
+        BranchGenState {
+            origin,
+            aged_origin: origin,
+            orientation: q_radial * Quat::from_axis_angle(Vec3::X, lean),
+            length: trunk.length * (1.0 - params.length_variance.clamp(0.0, 1.0) * rng.f32()),
+            noise_phases: noise_phases(settings, 0, rng),
+            ..trunk.clone()
+        }
+    }).collect()
+}
+
+/// Co-dominant stems continuing a forked trunk (from its last ring)
+#[allow(clippy::too_many_arguments)]
+fn generate_fork_stems(
+    settings: &TreeMeshSettings,
+    state: &BranchGenState,
+    origin: Vec3,
+    orientation: Quat,
+    radius: f32,
+    fork_section: usize,
+    v_start: f32,
+    rng: &mut Rng,
+) -> Vec<BranchGenState> {
+    let fork_angle = settings.trunk.fork_angle.to_radians();
+    let radial_offset = rng.f32();
+    // keep roughly the same cross-sectional area as the forked trunk
+    let stem_radius = radius / FORK_STEMS.sqrt();
+
+    (0..FORK_STEMS as usize).map(|i| {
+        let radial_angle = 2.0 * PI * (radial_offset + i as f32 / FORK_STEMS);
+        let q1 = Quat::from_axis_angle(Vec3::X, fork_angle * (0.75 + 0.5 * rng.f32()));
+        let q2 = Quat::from_axis_angle(Vec3::Y, radial_angle);
+        BranchGenState {
+            origin,
+            orientation: orientation * q2 * q1,
+            length: state.length * (1.0 - settings.trunk.length_variance.clamp(0.0, 1.0) * rng.f32()),
+            start_radius: stem_radius,
+            v_start,
+            first_section: fork_section,
+            fork_depth: state.fork_depth + 1,
+            seam_ring: None,
+            ..state.clone()
+        }
+    }).collect()
+}
+
+/// Surface roots growing outward and downward from the base of the trunk
+fn generate_roots(settings: &TreeMeshSettings, bark_seed: u32, rng: &mut Rng) -> Vec<BranchGenState> {
+    let roots = &settings.roots;
+    if roots.count == 0 || roots.sections == 0 || roots.segments < 3 {
+        return Vec::new();
+    }
+
+    let trunk_radius = settings.branch.trunk_base_radius * (1.0 + roots.flare.max(0.0));
+    // start slightly above the ground, so that the roots emerge from the flare
+    let root_height = (roots.flare_height * 0.25).min(trunk_radius);
+    let radial_offset: f32 = rng.f32();
+
+    (0..roots.count).map(|i| {
+        let radial_angle = 2.0 * PI * (radial_offset + (i as f32 + (rng.f32() - 0.5) * 0.5) / roots.count as f32);
+        let (sin, cos) = radial_angle.sin_cos();
+        let outward = Vec3::new(cos, 0.0, sin);
+        let down_angle = roots.angle.to_radians();
+        let direction = outward * down_angle.cos() - Vec3::Y * down_angle.sin();
+        let origin = Vec3::new(0.0, root_height, 0.0);
+
+        BranchGenState {
+            origin,
+            orientation: Quat::from_rotation_arc(Vec3::Y, direction.normalize()),
+            length: roots.length * (0.75 + 0.5 * rng.f32()),
+            start_radius: settings.branch.trunk_base_radius * roots.radius_factor,
+            taper: roots.taper,
+            twist: 0.0,
+            gnarliness: roots.gnarliness,
+            level: 1,
+            recursion_count: 1,
+            sections: roots.sections as usize,
+            segments: roots.segments as usize,
+            v_start: 0.0,
+            parent_joint: Some(ParentJoint {
+                origin,
+                axis: Vec3::Y,
+                radius: trunk_radius,
+            }),
+            is_root: true,
+            bark_seed,
+            fork_depth: 0,
+            first_section: 0,
+            first_ring: 0,
+            // roots are always round
+            noise_phases: [0.0; NOISE_HARMONICS],
+            birth: 0.0,
+            aged_origin: origin,
+            seam_ring: None,
+        }
+    }).collect()
+}
+
+/// Move a vertex of the first ring of a child branch along the branch direction onto the (cylindrical) surface of its parent
+fn weld_onto_parent(vertex: Vec3, direction: Vec3, parent_joint: &ParentJoint, max_shift: f32) -> Vec3 {
+    // solve |perp(vertex + direction * t - origin)| = radius for t; perp removes the part along the parent axis
+    let perp = |x: Vec3| x - parent_joint.axis * x.dot(parent_joint.axis);
+    let w = perp(vertex - parent_joint.origin);
+    let e = perp(direction);
+    let a = e.length_squared();
+    if a < f32::EPSILON {
+        // branch parallel to its parent
+        return vertex;
+    }
+    let b = 2.0 * w.dot(e);
+    let c = w.length_squared() - parent_joint.radius * parent_joint.radius;
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return vertex;
+    }
+    // the larger solution is where the branch leaves the parent
+    let t = (-b + discriminant.sqrt()) / (2.0 * a);
+    vertex + direction * t.clamp(-max_shift, max_shift)
+}
+
+/// Create the vertices (one ring per section) and triangles of a single branch
+/// 
+/// Returns the v-coordinate of the last ring (to continue the bark texture on following parts of the same branch).
+fn build_branch_mesh(
+    settings: &TreeMeshSettings,
+    state: &BranchGenState,
+    sections: &[SectionData],
+    growth_times: &[f32],
+    branches_attributes: &mut MeshAttributes,
+) -> f32
+{
+    let segments = state.segments;
+    let cross_section = if state.is_root { CrossSection::Circle } else { settings.branch.cross_section[state.level.min(3)] };
+    #[cfg(not(feature = "u32_indices"))]
+    let indices_start: u16 = branches_attributes.positions.len() as u16;
+    #[cfg(feature = "u32_indices")]
+    let indices_start: u32 = branches_attributes.positions.len() as u32;
+
+    let mut v = state.v_start;
+
+    // palm trunks have ring-segmented bark uvs
+    let segmented = settings.tree_type == TreeType::Palm && state.level == 0 && !state.is_root;
+    // section index of each ring of vertices
+    let mut emitted_rings: Vec<usize> = Vec::with_capacity(sections.len() * if segmented { 2 } else { 1 });
+
+    // the base of the trunk is flared (and lobed) towards the ground
+    let is_trunk_base = state.level == 0 && state.recursion_count == 0 && state.first_section == 0 && !state.is_root;
+
+    // collar around the joint to the parent branch (flared rings; the first ring is welded onto the surface of the parent)
+    let collar = match (settings.branch.joint.mode, state.parent_joint.as_ref()) {
+        (BranchJointMode::Collar, Some(parent_joint)) => Some(parent_joint),
+        _ => None,
+    };
+    let collar_length = settings.branch.joint.length.clamp(0.0, 1.0) * sections.windows(2).map(|w| w[0].origin.distance(w[1].origin)).sum::<f32>();
+
+    // distance along the branch, radius (with collar and flare) and depth of the flare lobes per ring
+    let mut distance_along_branch = 0.0;
+    let rings: Vec<(f32, f32, f32)> = sections.iter().enumerate().map(|(section_counter, section)| {
+        if section_counter > 0 {
+            distance_along_branch += sections[section_counter - 1].origin.distance(section.origin);
+        }
+        let collar_factor = match collar {
+            Some(_) if distance_along_branch < collar_length => 1.0 + settings.branch.joint.flare.max(0.0) * (1.0 - distance_along_branch / collar_length).powi(2),
+            _ => 1.0,
+        };
+        let ring_radius = section.radius * collar_factor;
+
+        // root flare: fades out over the flare height
+        let flare_fade = if is_trunk_base && settings.roots.flare > 0.0 && settings.roots.flare_height > 0.0 {
+            (1.0 - distance_along_branch / settings.roots.flare_height).clamp(0.0, 1.0).powi(2)
+        } else {
+            0.0
+        };
+        let ring_radius = ring_radius * (1.0 + settings.roots.flare * flare_fade);
+        let lobe_depth = settings.roots.lobe_depth.clamp(0.0, 1.0) * flare_fade;
+        (distance_along_branch, ring_radius, lobe_depth)
+    }).collect();
+
+    for (section_counter, section) in sections.iter().enumerate() {
+        let (_, ring_radius, lobe_depth) = rings[section_counter];
+
+        // change of the radius along the branch (between the neighbouring rings); tilts the normals of tapered branches
+        let (previous_distance, previous_radius, _) = rings[section_counter.saturating_sub(1)];
+        let (next_distance, next_radius, _) = rings[(section_counter + 1).min(rings.len() - 1)];
+        let radius_slope = if next_distance - previous_distance > f32::EPSILON {
+            (next_radius - previous_radius) / (next_distance - previous_distance)
+        } else {
+            0.0
+        };
+
+        // v-coordinate of this ring
+        v = match settings.branch.bark_uv_mode {
+            BarkUvMode::Alternating => if section_counter % 2 == 0 { 0.0 } else { 1.0 },
+            BarkUvMode::Continuous if section_counter == 0 => state.v_start,
+            BarkUvMode::Continuous => {
+                // world distance divided by the circumference at the start of the section (same texel density as along u)
+                let previous_section = &sections[section_counter - 1];
+                let circumference = 2.0 * PI * previous_section.radius.max(f32::EPSILON);
+                v + previous_section.origin.distance(section.origin) / (circumference * settings.branch.bark_texture_scale.max(f32::EPSILON))
+            },
+        };
+
+        // palm trunks: every section has its own full v-range (the rings at the borders of the sections are duplicated)
+        let ring_vs: Vec<f32> = match (segmented, section_counter) {
+            (false, _) => vec![v],
+            (true, 0) => vec![0.0],
+            (true, _) if section_counter == sections.len() - 1 => vec![1.0],
+            (true, _) => vec![1.0, 0.0],
+        };
+
+        // growth animation: the ring grows out of the center of the previous ring
+        let growth_pivot = sections[section_counter.saturating_sub(1)].origin.to_array();
+        let growth_time = [growth_times[section_counter.saturating_sub(1)], growth_times[section_counter]];
+
+        for ring_v in ring_vs {
+            emitted_rings.push(section_counter);
+            // one vertex per segment and the duplicated seam vertex
+            branches_attributes.growth_pivots.extend(std::iter::repeat_n(growth_pivot, segments + 1));
+            branches_attributes.growth_times.extend(std::iter::repeat_n(growth_time, segments + 1));
+
+            // save the first vertex to create a ring in the end
+            let mut first_pos = Vec3::ZERO;
+            let mut first_nrm = Vec3::ZERO;
+    
+            // for each segment create a single vertex
+            for segment_counter in 0..segments {
+                let angle = (2.0 * PI * segment_counter as f32) / segments as f32;
+                let (sin, cos) = angle.sin_cos();    
+            
+                // radius and its derivative along the ring (the normal follows the actual shape of the ring)
+                let (flare_profile, flare_derivative) = lobed_profile(angle, settings.roots.lobes, lobe_depth);
+                let (shape_profile, shape_derivative) = cross_section_profile(cross_section, angle, &state.noise_phases, state.first_ring + state.first_section + section_counter);
+                let profile = flare_profile * shape_profile;
+                let profile_derivative = flare_derivative * shape_profile + flare_profile * shape_derivative;
+                let local_pos = Vec3::new(cos * ring_radius * profile, 0.0, sin * ring_radius * profile);
+                let local_normal = Vec3::new(
+                    profile * cos + profile_derivative * sin,
+                    -profile * profile * radius_slope,
+                    profile * sin - profile_derivative * cos
+                );
+    
+                let mut vertex = (section.orientation * local_pos) + section.origin;
+                if let (Some(parent_joint), 0, Some(next_section)) = (collar, section_counter, sections.get(1)) {
+                    let max_shift = section.origin.distance(next_section.origin) * 0.9;
+                    vertex = weld_onto_parent(vertex, section.orientation * Vec3::Y, parent_joint, max_shift);
+                }
+                let normal = (section.orientation * local_normal).normalize();
+    
+                let u = segment_counter as f32 / segments as f32;
+        
+                if segment_counter == 0 {
+                    first_pos = vertex;
+                    first_nrm = normal;
+                }            
+                branches_attributes.positions.push(vertex.to_array());
+                branches_attributes.normals.push(normal.to_array());
+                branches_attributes.uvs.push([u, ring_v]);
+            } // END for each segment
+    
+            // duplicate of the first vertex to create a full ring (with different uv)
+            branches_attributes.positions.push(first_pos.to_array());
+            branches_attributes.normals.push(first_nrm.to_array());
+            branches_attributes.uvs.push([1.0, ring_v]);
+        } // END for each emitted ring
+    } // END for each section
+    
+    let displacement = &settings.branch.displacement;
+    if displacement.enabled && state.level <= displacement.max_level as usize {
+        displace_bark(settings, state, sections, &emitted_rings, indices_start as usize, branches_attributes);
+    }
+
+    // Indices (triangles) are build around the ring per segment
+    // (duplicated rings of segmented uvs are not connected to each other)
+    let ring_count = emitted_rings.len();
+    let connected_rings: Vec<usize> = (0..ring_count.saturating_sub(1)).step_by(if segmented { 2 } else { 1 }).collect();
+    #[cfg(not(feature = "u32_indices"))]
+    {
+        let ring_stride: u16 = segments as u16 + 1;    
+        for i in connected_rings.iter().map(|i| *i as u16) {
+            for j in 0..segments as u16 {
+                let a: u16 = i * ring_stride        + j         + indices_start;
+                let b: u16 = i * ring_stride        + (j + 1)   + indices_start;
+                let c: u16 = a + ring_stride;
+                let d: u16 = b + ring_stride;
+        
+                branches_attributes.indices.extend_from_slice(&[a, c, b, b, c, d]);
+            }
+        }
+    }  
+    #[cfg(feature = "u32_indices")]
+    {
+        let ring_stride: u32 = segments as u32 + 1;    
+        for i in connected_rings.iter().map(|i| *i as u32) {
+            for j in 0..segments as u32 {
+                let a: u32 = i * ring_stride        + j         + indices_start;
+                let b: u32 = i * ring_stride        + (j + 1)   + indices_start;
+                let c: u32 = a + ring_stride;
+                let d: u32 = b + ring_stride;
+        
+                branches_attributes.indices.extend_from_slice(&[a, c, b, b, c, d]);
+            }
+        }
+    }  
+
+    if segmented { 1.0 } else { v }
+}
+
+
+/// Close the last ring of a branch (the last vertices of branches_attributes) with a flat, domed or jagged cap
+fn build_end_cap(
+    settings: &TreeMeshSettings,
+    end_cap: EndCap,
+    section: &SectionData,
+    segments: usize,
+    rng: &mut Rng,
+    branches_attributes: &mut MeshAttributes,
+) {
+    if matches!(end_cap, EndCap::None) || segments < 3 {
+        return;
+    }
+    let height = settings.branch.end_cap_height.max(0.0) * section.radius;
+    let axis = section.orientation * Vec3::Y;
+    // the last ring has a duplicated seam vertex
+    let last_ring_start = branches_attributes.positions.len() - (segments + 1);
+    let last_ring: Vec<Vec3> = branches_attributes.positions[last_ring_start..last_ring_start + segments].iter().map(|p| Vec3::from_array(*p)).collect();
+
+    // inner rings: (relative radius, height per vertex)
+    let cap_rings: Vec<(f32, Vec<f32>)> = match end_cap {
+        EndCap::None => return,
+        EndCap::Flat => vec![(1.0, vec![0.0; segments])],
+        EndCap::Dome => (0..END_CAP_DOME_RINGS).map(|ring| {
+            let phi = 0.5 * PI * ring as f32 / END_CAP_DOME_RINGS as f32;
+            (phi.cos(), vec![height * phi.sin(); segments])
+        }).collect(),
+        // jagged caps: random height per vertex
+        EndCap::Jagged => vec![
+            (1.0, vec![0.0; segments]),
+            (0.6, (0..segments).map(|_| height * rng.f32()).collect()),
+        ],
+    };
+    let center_height = match end_cap {
+        EndCap::Dome => height,
+        EndCap::Jagged => height * 0.3 * rng.f32(),
+        _ => 0.0,
+    };
+
+    let cap_start = branches_attributes.positions.len();
+    for (relative_radius, heights) in &cap_rings {
+        for (segment_counter, ring_vertex) in last_ring.iter().enumerate() {
+            let radial = *ring_vertex - section.origin;
+            let position = section.origin + radial * *relative_radius + axis * heights[segment_counter];
+            let normal = match end_cap {
+                EndCap::Dome => (radial.normalize_or_zero() * *relative_radius + axis * (1.0 - relative_radius * relative_radius).sqrt()).normalize_or(axis),
+                _ => axis,
+            };
+            let angle = (2.0 * PI * segment_counter as f32) / segments as f32;
+            branches_attributes.positions.push(position.to_array());
+            branches_attributes.normals.push(normal.to_array());
+            branches_attributes.uvs.push([0.5 + 0.5 * relative_radius * angle.cos(), 0.5 + 0.5 * relative_radius * angle.sin()]);
+        }
+    }
+    let center = branches_attributes.positions.len();
+    branches_attributes.positions.push((section.origin + axis * center_height).to_array());
+    branches_attributes.normals.push(axis.to_array());
+    branches_attributes.uvs.push([0.5, 0.5]);
+
+    // triangles between the rings (same winding as the rings of the branch) and a fan to the center
+    let mut triangles: Vec<usize> = Vec::with_capacity(cap_rings.len() * segments * 6);
+    for ring in 0..cap_rings.len() {
+        for j in 0..segments {
+            let a = cap_start + ring * segments + j;
+            let b = cap_start + ring * segments + (j + 1) % segments;
+            if ring + 1 < cap_rings.len() {
+                let c = a + segments;
+                let d = b + segments;
+                triangles.extend_from_slice(&[a, c, b, b, c, d]);
+            } else {
+                triangles.extend_from_slice(&[a, center, b]);
+            }
+        }
+    }
+    #[cfg(not(feature = "u32_indices"))]
+    branches_attributes.indices.extend(triangles.iter().map(|i| *i as u16));
+    #[cfg(feature = "u32_indices")]
+    branches_attributes.indices.extend(triangles.iter().map(|i| *i as u32));
+}
+
+/// Offset the ring vertices of a branch (starting at first_vertex) away from the axis with seeded 3d noise and recalculate the normals from the displaced rings
+/// 
+/// ridges/furrows are stretched along the branch; knots are sparse bumps
+/// 
+/// the noise only depends on the world position (and the seed of the tree), so the shared rings of the trunk parts are displaced identically
+/// and their normals are averaged (see BranchGenState::seam_ring)
+fn displace_bark(
+    settings: &TreeMeshSettings,
+    state: &BranchGenState,
+    sections: &[SectionData],
+    emitted_rings: &[usize],
+    first_vertex: usize,
+    branches_attributes: &mut MeshAttributes,
+) {
+    let params = &settings.branch.displacement;
+    let ring_stride = state.segments + 1;
+    let knot_threshold = 1.0 - params.knot_density.clamp(0.0, 1.0);
+
+    for (ring, section) in emitted_rings.iter().map(|section_index| &sections[*section_index]).enumerate() {
+        // thin branches get proportionally less displacement
+        let radius_scale = (section.radius / settings.branch.trunk_base_radius.max(f32::EPSILON)).clamp(0.0, 1.0);
+        let axis = section.orientation * Vec3::Y;
+        for segment_counter in 0..ring_stride {
+            let index = first_vertex + ring * ring_stride + segment_counter;
+            let position = Vec3::from_array(branches_attributes.positions[index]);
+            // away from the axis (unlike the normal, the same for the rings on both sides of a seam)
+            let radial = (position - section.origin).reject_from_normalized(axis).try_normalize()
+                .unwrap_or_else(|| Vec3::from_array(branches_attributes.normals[index]));
+
+            // ridges and furrows: ridged noise, compressed around the branch and stretched along it
+            let along = axis * position.dot(axis);
+            let ridge_position = (position - along + along / params.ridge_stretch.max(f32::EPSILON)) * params.ridge_frequency;
+            let ridge = (1.0 - (2.0 * fbm(ridge_position, state.bark_seed, 2) - 1.0).abs()).powf(params.ridge_sharpness.max(f32::EPSILON));
+
+            // knots: only the highest peaks of a low frequency noise
+            let knot_noise = value_noise(position * params.knot_frequency, state.bark_seed.wrapping_add(KNOT_SEED_OFFSET));
+            let knot = ((knot_noise - knot_threshold) / (1.0 - knot_threshold).max(f32::EPSILON)).max(0.0).powi(2);
+
+            let offset = (params.strength * (ridge - 0.5) + params.knot_strength * knot) * radius_scale;
+            branches_attributes.positions[index] = (position + radial * offset).to_array();
+        }
+        // the duplicated seam vertex is at the same position as the first vertex of the ring
+        let ring_start = first_vertex + ring * ring_stride;
+        branches_attributes.positions[ring_start + state.segments] = branches_attributes.positions[ring_start];
+    }
+
+    // normals from the neighbouring vertices (around and along the branch)
+    let position = |ring: usize, segment: usize| Vec3::from_array(branches_attributes.positions[first_vertex + ring * ring_stride + segment % state.segments]);
+    let mut normals = Vec::with_capacity(emitted_rings.len() * ring_stride);
+    for ring in 0..emitted_rings.len() {
+        for segment in 0..ring_stride {
+            let around = position(ring, segment + 1) - position(ring, segment + state.segments - 1);
+            let along = position((ring + 1).min(emitted_rings.len() - 1), segment) - position(ring.saturating_sub(1), segment);
+            let original = Vec3::from_array(branches_attributes.normals[first_vertex + ring * ring_stride + segment]);
+            let normal = along.cross(around).try_normalize().unwrap_or(original);
+            // keep the normal on the outside
+            normals.push(if normal.dot(original) < 0.0 { -normal } else { normal });
+        }
+    }
+    for (index, normal) in normals.into_iter().enumerate() {
+        branches_attributes.normals[first_vertex + index] = normal.to_array();
+    }
+
+    // the first ring continues the last ring of the previous part of the trunk: both get the average of their (one-sided) normals
+    if let Some(seam_ring) = state.seam_ring.filter(|seam_ring| seam_ring + ring_stride <= first_vertex) {
+        for segment in 0..ring_stride {
+            let (previous, current) = (seam_ring + segment, first_vertex + segment);
+            let normal = (Vec3::from_array(branches_attributes.normals[previous]) + Vec3::from_array(branches_attributes.normals[current]))
+                .try_normalize()
+                .unwrap_or(Vec3::from_array(branches_attributes.normals[current]));
+            branches_attributes.normals[previous] = normal.to_array();
+            branches_attributes.normals[current] = normal.to_array();
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn generate_child_branches (
+    count: u8,
+    level: usize,
+    parent_sections: &[SectionData],
+    parent: &BranchGenState,
+    settings: &TreeMeshSettings,
+    context: &TreeGenerationContext,
+    shade_grid: &ShadeGrid,
+    rng: &mut Rng,
+) -> Vec<BranchGenState> {
+    if count == 0 || parent_sections.is_empty(){
+        return Vec::new();
+    }
+
+    let radial_offset: f32 = rng.f32(); 
+    let section_count_minus_one: usize = parent_sections.len().saturating_sub(1);  
+
+    let mut out = Vec::with_capacity(count as usize);
+    for i in 0..count {
+        // lowest start position along the parent branch as a factor
+        let child_start_factor = f32::lerp(settings.branch.start[level].clamp(0.0, 1.0), 1.0, rng.f32());
+
+        // calculate a factor between two sections based on the possible range
+        let child_branch_pos = child_start_factor * section_count_minus_one as f32;
+        let section_index = child_branch_pos.floor() as usize;
+        let branch_height_factor = (child_branch_pos - section_index as f32).clamp(0.0, 1.0);
+
+        // calculate target sections where to place the branch
+        let section_a_index = section_index; 
+        let section_b_index = (section_index + 1).min(section_count_minus_one);
+        let section_a = &parent_sections[section_a_index];
+        let section_b = &parent_sections[section_b_index];
+
+        // interpolate the placement between section a and b
+        let child_branch_origin = section_a.origin.lerp(section_b.origin, branch_height_factor);
+
+        // calculate radius
+        // TODO is this correct?
+        let radius_setting = settings.branch.radius_factor[level];
+        let parent_radius = f32::lerp(section_a.radius, section_b.radius, branch_height_factor);
+        let child_branch_radius = radius_setting * parent_radius;
+
+        // orient along the parent sections
+        let parent_orientation = section_b.orientation.slerp(section_a.orientation, branch_height_factor);
+
+        // calculate needed angles 
+        let radial_angle = 2.0 * std::f32::consts::PI * (radial_offset + (i as f32) / (count as f32));
+        let angle_rad = settings.branch.angle[level].to_radians();
+        let q1 = Quat::from_axis_angle(Vec3::X, angle_rad);
+        let q2 = Quat::from_axis_angle(Vec3::Y, radial_angle);
+        let child_quat = parent_orientation * q2 * q1;
+
+        // target length
+        let mut child_len = settings.branch.length[level];
+        if settings.tree_type == TreeType::Evergreen {
+            child_len *= 1.0 - child_start_factor;
+        }
+
+        // shaded branches grow shorter and sparser
+        let photo = &settings.phototropism;
+        if grows_in_shade(settings) {
+            let shade = estimate_shade(context, shade_grid, child_branch_origin, photo.direction.normalize_or(Vec3::Y));
+            if photo.shade_density_reduction > 0.0 && rng.f32() < shade * photo.shade_density_reduction.clamp(0.0, 1.0) {
+                continue;
+            }
+            child_len *= 1.0 - shade * photo.shade_length_reduction.clamp(0.0, 1.0);
+        }
+
+        out.push(BranchGenState {
+            origin: child_branch_origin,
+            orientation: child_quat,
+            length: child_len,
+            start_radius: child_branch_radius,
+            level,
+            recursion_count: level,
+            taper: settings.branch.taper[level],
+            twist: settings.branch.twist[level],
+            gnarliness: settings.branch.gnarliness[level],
+            sections: settings.branch.sections[level].into(),
+            segments: settings.branch.segments[level].into(),
+            v_start: 0.0,
+            parent_joint: Some(ParentJoint {
+                origin: child_branch_origin,
+                axis: parent_orientation * Vec3::Y,
+                radius: parent_radius,
+            }),
+            is_root: false,
+            bark_seed: parent.bark_seed,
+            fork_depth: 0,
+            first_section: 0,
+            first_ring: 0,
+            noise_phases: noise_phases(settings, level, rng),
+            // the child starts growing when the parent has grown up to it
+            birth: parent.birth + growth_span(settings, parent) * child_start_factor,
+            aged_origin: child_branch_origin,
+            seam_ring: None,
+        });
+    }
+
+    out
+}
+
+/// whether shaded branches grow shorter or sparser (see Phototropism)
+fn grows_in_shade(settings: &TreeMeshSettings) -> bool {
+    settings.phototropism.shade_length_reduction > 0.0 || settings.phototropism.shade_density_reduction > 0.0
+}
+
+/// How much a child branch is shaded at its origin: 0 = in the light .. 1 = completely shaded
+/// 
+/// The light is blocked by the obstacles and by the branches and leaves generated before this branch (i.e. the upper parts of a deciduous trunk with their crown).
+fn estimate_shade(context: &TreeGenerationContext, shade_grid: &ShadeGrid, origin: Vec3, light: Vec3) -> f32 {
+    if ray_hits_obstacles(&context.obstacles, origin, light, SHADE_DISTANCE) {
+        return 1.0;
+    }
+    shade_grid.shade(origin, SHADE_DISTANCE)
+}
+
+fn generate_leaves(
+    sections: &[SectionData],
+    settings: &TreeMeshSettings,
+    context: &TreeGenerationContext,
+    rng: &mut Rng,
+    leaves_attributes: &mut MeshAttributes
+) -> Result<(), BevyError>
+{
+    // catch going outside of the allowed range early and tell the user
+    let approx_amount_of_indices_of_this_leaf: usize = match settings.leaves.leaf_billboard {
+        LeafBillboard::Single | LeafBillboard::Double | LeafBillboard::Billboard => settings.leaves.count as usize * (settings.leaves.shape.outline().len() - 2) * 3 * 2,
+        LeafBillboard::Needles => settings.leaves.count as usize * NEEDLE_STRIPS_PER_CLUSTER * 6,
+        LeafBillboard::ScaleFan => settings.leaves.count as usize * (SCALE_FAN_ARC_POINTS - 1) * 3,
+        LeafBillboard::BottleBrush => sections.len() * 2 * 6,
+    };
+    #[cfg(not(feature = "u32_indices"))]
+    if leaves_attributes.indices.len() >= (u16::MAX as usize - approx_amount_of_indices_of_this_leaf) {
+        return Err(IndicesOverflowError.into());
+    }
+    #[cfg(feature = "u32_indices")]
+    if leaves_attributes.indices.len() >= (u32::MAX as usize - approx_amount_of_indices_of_this_leaf) {
+        return Err(IndicesOverflowError.into());
+    }
+
+    if settings.leaves.leaf_billboard == LeafBillboard::BottleBrush {
+        // one brush per twig instead of single leaves
+        return generate_bottle_brush(sections, settings, rng, leaves_attributes);
+    }
+
+    let radial_offset: f32 = rng.f32();
+    let section_count_minus_one: usize = sections.len().saturating_sub(1);  
+
+    for i in 0..settings.leaves.count {
+        // how far along the section should this leaf start
+        let leaf_start = f32::lerp(settings.leaves.start.clamp(0.0, 1.0), 1.0, rng.f32());
+
+        // find relevant sections depending on leaf_start
         let leaf_pos = leaf_start * section_count_minus_one as f32;
         let section_index = leaf_pos.floor() as usize;
         let leaf_height_factor = (leaf_pos - section_index as f32).clamp(0.0, 1.0);
@@ -465,6 +1918,11 @@ fn generate_leaves(
         let q2 = Quat::from_axis_angle(Vec3::Y, radial_angle);
         let child_quat = parent_orientation * q2 * q1;
 
+        // no leaves inside of obstacles
+        if distance_to_obstacles(&context.obstacles, leaf_origin) < 0.0 {
+            continue;
+        }
+
         generate_leaf(settings, leaf_origin, child_quat, rng, leaves_attributes)?;
     }
 
@@ -479,46 +1937,480 @@ fn generate_leaf(
     leaves_attributes: &mut MeshAttributes
 ) -> Result<(), BevyError>
 {
+    let leaf_size_variance = (2.0 * rng.f32() - 1.0) * settings.leaves.size_variance.max(0.0);
+    let leaf_size = settings.leaves.size * (1.0 + leaf_size_variance);
+
+    // turn the face of the card (either side) towards the light
+    let orientation = if settings.phototropism.leaf_strength > 0.0 {
+        let light = settings.phototropism.direction.normalize_or(Vec3::Y);
+        let normal = orientation * Vec3::Z;
+        let facing = if normal.dot(light) < 0.0 { -normal } else { normal };
+        Quat::IDENTITY.slerp(Quat::from_rotation_arc(facing, light), settings.phototropism.leaf_strength.clamp(0.0, 1.0)) * orientation
+    } else {
+        orientation
+    };
+
+    // pick the texture region of this leaf (all cards of a leaf share the same variant)
+    let uv_rect = match settings.leaves.atlas {
+        Some(ref atlas) => pick_leaf_atlas_variant(atlas, rng),
+        None => Rect::new(0.0, 0.0, 1.0, 1.0),
+    };
+
+    // cards of this leaf: rotation around the leaf axis, outline in leaf uv-space (0..1; the base of the leaf is at v=1) and width factor
+    let scale_fan_outline = scale_fan_outline();
+    let cards: Vec<(f32, &[Vec2], f32)> = match settings.leaves.leaf_billboard {
+        LeafBillboard::Single | LeafBillboard::Billboard => vec![(0.0, settings.leaves.shape.outline(), 1.0)],
+        // a single bottle brush leaf (i.e. the top of a deciduous trunk) is rendered as crossed cards
+        LeafBillboard::Double | LeafBillboard::BottleBrush => vec![
+            (0.0, settings.leaves.shape.outline(), 1.0),
+            (f32::consts::FRAC_PI_2, settings.leaves.shape.outline(), 1.0),
+        ],
+        LeafBillboard::Needles => (0..NEEDLE_STRIPS_PER_CLUSTER)
+            .map(|i| (i as f32 * PI / NEEDLE_STRIPS_PER_CLUSTER as f32, LeafShape::Quad.outline(), NEEDLE_STRIP_WIDTH))
+            .collect(),
+        LeafBillboard::ScaleFan => vec![(0.0, &scale_fan_outline[..], 1.0)],
+    };
+
+    for (rotation, outline, width_factor) in cards {
+        let leaf_orientation = orientation * Quat::from_euler(EulerRot::XYX, 0.0, rotation, 0.0);
+
+        // vertice positions (relative to the leaf origin in the plane of the card)
+        let local_positions: Vec<Vec3> = outline.iter().map(|p| Vec3::new((p.x - 0.5) * leaf_size * width_factor, (1.0 - p.y) * leaf_size, 0.0)).collect();
+        let vertices: Vec<Vec3> = local_positions.iter().map(|v| leaf_orientation * *v + origin).collect();
+
+        // vertice normals
+        let normal: Vec3 = leaf_orientation * Vec3::new(0.0, 0.0, 1.0);
+        let normals: Vec<Vec3> = vertices.iter().map(|v| leaf_vertex_normal(settings, *v, origin, normal)).collect();
+
+        // uvs (mapped to the atlas variant)
+        let uvs: Vec<[f32;2]> = outline.iter()
+            .map(|p| [uv_rect.min.x + p.x * uv_rect.width(), uv_rect.min.y + p.y * uv_rect.height()])
+            .collect();
+
+        // the triangles have to face in the direction of the normal (+z) regardless of the order of the provided outline (v points down in the card)
+        let card_outline: Vec<Vec2> = outline.iter().map(|p| Vec2::new(p.x * width_factor, -p.y)).collect();
+        let triangles = triangulate_polygon(&card_outline).ok_or(LeafShapeError)?;
+
+        push_leaf_geometry(leaves_attributes, &vertices, &normals, &uvs, &triangles, origin);
+
+        if settings.leaves.leaf_billboard == LeafBillboard::Billboard {
+            // the quad is rotated towards the camera around its center by the vertex shader (see LeafBillboardMaterial)
+            let center = leaf_orientation * Vec3::new(0.0, leaf_size / 2.0, 0.0) + origin;
+            leaves_attributes.leaf_centers.extend(std::iter::repeat_n(center.to_array(), vertices.len()));
+            leaves_attributes.leaf_corners.extend(local_positions.iter().map(|p| [p.x, p.y - leaf_size / 2.0]));
+        }
+    }
+
+    Ok(())
+}
+
+/// Crown of a palm: arched fronds (a thin stem strip with leaflets on both sides) in a spiral around the top of the trunk
+fn generate_fronds(
+    settings: &TreeMeshSettings,
+    origin: Vec3,
+    orientation: Quat,
+    rng: &mut Rng,
+    leaves_attributes: &mut MeshAttributes
+) -> Result<(), BevyError>
+{
+    let params = &settings.palm;
+    let leaflets = params.leaflets as usize;
+
+    // catch going outside of the allowed range early and tell the user
+    let approx_amount_of_indices_of_the_crown: usize = params.frond_count as usize * (FROND_SAMPLES * 6 + leaflets * 2 * 6);
     #[cfg(not(feature = "u32_indices"))]
-    let mut indices_start: u16 = leaves_attributes.positions.len() as u16;
+    if leaves_attributes.indices.len() >= (u16::MAX as usize - approx_amount_of_indices_of_the_crown) {
+        return Err(IndicesOverflowError.into());
+    }
     #[cfg(feature = "u32_indices")]
-    let mut indices_start: u32 = leaves_attributes.positions.len() as u32;
+    if leaves_attributes.indices.len() >= (u32::MAX as usize - approx_amount_of_indices_of_the_crown) {
+        return Err(IndicesOverflowError.into());
+    }
+
+    let trunk_up = orientation * Vec3::Y;
+    let radial_offset = rng.f32() * 2.0 * PI;
+
+    for frond in 0..params.frond_count {
+        let uv_rect = match settings.leaves.atlas {
+            Some(ref atlas) => pick_leaf_atlas_variant(atlas, rng),
+            None => Rect::new(0.0, 0.0, 1.0, 1.0),
+        };
+        let length = params.frond_length * (1.0 + (2.0 * rng.f32() - 1.0) * settings.leaves.size_variance.max(0.0));
+
+        // fronds in a spiral (golden angle); the younger fronds at the top point upwards, the older ones hang lower
+        let age = frond as f32 / params.frond_count.max(1) as f32;
+        let radial_angle = radial_offset + frond as f32 * GOLDEN_ANGLE;
+        let elevation = f32::lerp(params.frond_angle, -params.frond_angle * 0.5, age).to_radians();
+        let outward = orientation * Vec3::new(radial_angle.cos(), 0.0, radial_angle.sin());
+        let direction = (outward * elevation.cos() + trunk_up * elevation.sin()).normalize();
+        // side of the frond (horizontal) and the normal of the plane of the frond
+        let side = direction.cross(Vec3::Y).try_normalize().unwrap_or(orientation * Vec3::X);
 
+        // arched rachis: straight direction pulled down by the droop
+        let rachis: Vec<(Vec3, Vec3)> = (0..=FROND_SAMPLES).map(|i| {
+            let t = i as f32 / FROND_SAMPLES as f32;
+            let position = origin + direction * length * t - Vec3::Y * params.frond_droop * length * t * t;
+            let tangent = (direction - Vec3::Y * 2.0 * params.frond_droop * t).normalize();
+            (position, tangent)
+        }).collect();
+
+        // rachis strip (center column of the leaf texture)
+        let mut vertices: Vec<Vec3> = Vec::with_capacity(rachis.len() * 2);
+        let mut normals: Vec<Vec3> = Vec::with_capacity(rachis.len() * 2);
+        let mut uvs: Vec<[f32;2]> = Vec::with_capacity(rachis.len() * 2);
+        for (i, (position, tangent)) in rachis.iter().enumerate() {
+            let t = i as f32 / FROND_SAMPLES as f32;
+            let half_width = params.rachis_width * (1.0 - 0.7 * t) / 2.0;
+            let normal = side.cross(*tangent).normalize();
+            let v = uv_rect.min.y + (1.0 - t) * uv_rect.height();
+            for (vertex, u) in [(*position - side * half_width, 0.45), (*position + side * half_width, 0.55)] {
+                vertices.push(vertex);
+                normals.push(leaf_vertex_normal(settings, vertex, origin, normal));
+                uvs.push([uv_rect.min.x + u * uv_rect.width(), v]);
+            }
+        }
+        let triangles: Vec<usize> = (0..FROND_SAMPLES)
+            .flat_map(|i| {
+                let (a, b, c, d) = (2 * i, 2 * i + 1, 2 * i + 2, 2 * i + 3);
+                [a, b, d, a, d, c]
+            })
+            .collect();
+        push_leaf_geometry(leaves_attributes, &vertices, &normals, &uvs, &triangles, origin);
+
+        // leaflets on both sides (shorter at the base and the tip of the frond)
+        let leaflet_droop = params.leaflet_droop.to_radians();
+        for leaflet in 0..leaflets {
+            let t = (leaflet as f32 + 0.5) / leaflets as f32;
+            let sample = (t * FROND_SAMPLES as f32).min(FROND_SAMPLES as f32 - 1.0);
+            let index = sample.floor() as usize;
+            let position = rachis[index].0.lerp(rachis[index + 1].0, sample - index as f32);
+            let tangent = rachis[index].1.lerp(rachis[index + 1].1, sample - index as f32).normalize();
+            let frond_normal = side.cross(tangent).normalize();
+            let leaflet_length = params.leaflet_length * (PI * (0.1 + 0.9 * t)).sin().max(0.2);
+
+            for side_sign in [-1.0, 1.0] {
+                // angled towards the tip of the frond and drooping below the plane of the frond
+                let leaflet_direction = ((side * side_sign * 0.85 + tangent * 0.5).normalize() * leaflet_droop.cos() - frond_normal * leaflet_droop.sin()).normalize();
+                let mut width_axis = (tangent - leaflet_direction * tangent.dot(leaflet_direction)).normalize_or(tangent);
+                // the card faces upwards
+                if width_axis.cross(leaflet_direction).dot(frond_normal) < 0.0 {
+                    width_axis = -width_axis;
+                }
+                let normal = width_axis.cross(leaflet_direction).normalize();
+                let half_width = width_axis * params.leaflet_width / 2.0;
+                let tip = position + leaflet_direction * leaflet_length;
+                let vertices = [position - half_width, position + half_width, tip + half_width, tip - half_width];
+                let normals: Vec<Vec3> = vertices.iter().map(|v| leaf_vertex_normal(settings, *v, position, normal)).collect();
+                let uvs = [
+                    [uv_rect.min.x, uv_rect.max.y],
+                    [uv_rect.max.x, uv_rect.max.y],
+                    [uv_rect.max.x, uv_rect.min.y],
+                    [uv_rect.min.x, uv_rect.min.y],
+                ];
+                push_leaf_geometry(leaves_attributes, &vertices, &normals, &uvs, &[0, 1, 2, 0, 2, 3], origin);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Conifer foliage: two crossed cards wrapped around the given (last-level) twig from the leaf start to its tip
+fn generate_bottle_brush(
+    sections: &[SectionData],
+    settings: &TreeMeshSettings,
+    rng: &mut Rng,
+    leaves_attributes: &mut MeshAttributes
+) -> Result<(), BevyError>
+{
     let leaf_size_variance = (2.0 * rng.f32() - 1.0) * settings.leaves.size_variance.max(0.0);
-    let leaf_size = settings.leaves.size * (1.0 + leaf_size_variance);
-    let leaf_size_half = leaf_size / 2.0;
+    let half_width = settings.leaves.size * (1.0 + leaf_size_variance) / 2.0;
 
-    let rotations: &[f32] = match settings.leaves.leaf_billboard {
-        crate::enums::LeafBillboard::Single => &[0.0],
-        crate::enums::LeafBillboard::Double => &[0.0, f32::consts::FRAC_PI_2],
+    let uv_rect = match settings.leaves.atlas {
+        Some(ref atlas) => pick_leaf_atlas_variant(atlas, rng),
+        None => Rect::new(0.0, 0.0, 1.0, 1.0),
     };
 
-    for rotation in rotations.iter() {
-        let leaf_orientation = orientation * Quat::from_euler(EulerRot::XYX, 0.0, *rotation, 0.0);
+    // sample the twig from the leaf start to the tip
+    let section_count_minus_one: usize = sections.len().saturating_sub(1);
+    let start_pos = settings.leaves.start.clamp(0.0, 1.0) * section_count_minus_one as f32;
+    let start_index = start_pos.floor() as usize;
+    let start_factor = start_pos - start_index as f32;
+    let start_section = &sections[start_index];
+    let next_section = &sections[(start_index + 1).min(section_count_minus_one)];
 
-        // vertice positions
-        let vertices: Vec<[f32;3]> = [
-        Vec3::new(-leaf_size_half, leaf_size, 0.0),
-        Vec3::new(-leaf_size_half, 0.0, 0.0),
-        Vec3::new(leaf_size_half, 0.0, 0.0),
-        Vec3::new(leaf_size_half, leaf_size, 0.0),
-        ].into_iter().map(|v| (leaf_orientation * v + origin).to_array()).collect();
+    let mut samples: Vec<(Vec3, Quat)> = vec![(
+        start_section.origin.lerp(next_section.origin, start_factor),
+        next_section.orientation.slerp(start_section.orientation, start_factor),
+    )];
+    samples.extend(sections[(start_index + 1).min(sections.len())..].iter().map(|section| (section.origin, section.orientation)));
+    if samples.len() < 2 {
+        return Ok(());
+    }
 
-        leaves_attributes.positions.extend_from_slice(&vertices);
+    // v runs from the base (1.0) to the tip (0.0) like on a single leaf
+    let mut distances: Vec<f32> = vec![0.0];
+    for window in samples.windows(2) {
+        distances.push(distances[distances.len() - 1] + window[0].0.distance(window[1].0));
+    }
+    let total_length = distances[distances.len() - 1].max(f32::EPSILON);
 
-        // vertice normals
-        let normal: [f32;3] = (leaf_orientation * Vec3::new(0.0, 0.0, 1.0)).to_array();
+    for card_axis in [Vec3::X, Vec3::Z] {
+        let mut vertices: Vec<Vec3> = Vec::with_capacity(samples.len() * 2);
+        let mut normals: Vec<Vec3> = Vec::with_capacity(samples.len() * 2);
+        let mut uvs: Vec<[f32;2]> = Vec::with_capacity(samples.len() * 2);
+
+        for ((origin, orientation), distance) in samples.iter().zip(distances.iter()) {
+            let side = *orientation * card_axis * half_width;
+            let normal = *orientation * card_axis.cross(Vec3::Y);
+            let v = uv_rect.min.y + (1.0 - distance / total_length) * uv_rect.height();
+            for (vertex, u) in [(*origin - side, uv_rect.min.x), (*origin + side, uv_rect.max.x)] {
+                vertices.push(vertex);
+                normals.push(leaf_vertex_normal(settings, vertex, *origin, normal));
+                uvs.push([u, v]);
+            }
+        }
 
-        leaves_attributes.normals.push(normal);
-        leaves_attributes.normals.push(normal);
-        leaves_attributes.normals.push(normal);
-        leaves_attributes.normals.push(normal);
+        let triangles: Vec<usize> = (0..samples.len() - 1)
+            .flat_map(|i| {
+                let (a, b, c, d) = (2 * i, 2 * i + 1, 2 * i + 2, 2 * i + 3);
+                [a, b, d, a, d, c]
+            })
+            .collect();
 
-        // uvs and indices
-        leaves_attributes.uvs.extend_from_slice(&[[0.0, 0.0],[0.0, 1.0],[1.0, 1.0],[1.0, 0.0]]);
-        leaves_attributes.indices.extend_from_slice(&[indices_start, indices_start+1, indices_start+2, indices_start, indices_start+2, indices_start+3]);
-        indices_start += 4;
+        push_leaf_geometry(leaves_attributes, &vertices, &normals, &uvs, &triangles, samples[0].0);
     }
 
     Ok(())
+}
+
+/// Triangulate a simple polygon (convex or concave, in either order) by ear clipping; all triangles are counter-clockwise
+/// 
+/// none if the polygon is degenerate (less than 3 points, no area or self-intersecting)
+fn triangulate_polygon(points: &[Vec2]) -> Option<Vec<usize>> {
+    let count = points.len();
+    let edge = |i: usize| (points[i], points[(i + 1) % count]);
+    let signed_area: f32 = (0..count).map(|i| edge(i).0.perp_dot(edge(i).1)).sum::<f32>() / 2.0;
+    if count < 3 || signed_area.abs() <= f32::EPSILON {
+        return None;
+    }
+
+    // edges which are not neighbors must not touch
+    let crosses = |(a, b): (Vec2, Vec2), (c, d): (Vec2, Vec2)| {
+        let side = |p: Vec2, q: Vec2, r: Vec2| (q - p).perp_dot(r - p);
+        side(a, b, c) * side(a, b, d) < 0.0 && side(c, d, a) * side(c, d, b) < 0.0
+    };
+    let self_intersecting = (0..count).any(|i| (i + 2..count)
+        .filter(|j| (j + 1) % count != i)
+        .any(|j| crosses(edge(i), edge(j))));
+    if self_intersecting {
+        return None;
+    }
+
+    let inside = |p: Vec2, [a, b, c]: [Vec2; 3]| (b - a).perp_dot(p - a) >= 0.0 && (c - b).perp_dot(p - b) >= 0.0 && (a - c).perp_dot(p - c) >= 0.0;
+    let mut remaining: Vec<usize> = if signed_area > 0.0 { (0..count).collect() } else { std::iter::once(0).chain((1..count).rev()).collect() };
+    let mut triangles: Vec<usize> = Vec::with_capacity((count - 2) * 3);
+    while remaining.len() > 3 {
+        let n = remaining.len();
+        let corners = |i: usize| [remaining[(i + n - 1) % n], remaining[i], remaining[(i + 1) % n]];
+        // an ear is a convex corner without any other point inside of its triangle
+        // (a convex polygon becomes a fan around its first point)
+        let ear = (1..n).chain(0..1).find(|i| {
+            let triangle = corners(*i).map(|corner| points[corner]);
+            (triangle[1] - triangle[0]).perp_dot(triangle[2] - triangle[1]) > 0.0
+                && remaining.iter().map(|j| points[*j]).all(|p| triangle.contains(&p) || !inside(p, triangle))
+        })?;
+        triangles.extend(corners(ear));
+        remaining.remove(ear);
+    }
+    triangles.extend(remaining);
+    Some(triangles)
+}
+
+/// outline (in leaf uv-space) of a fan shaped scale-leaf card for cedars/cypresses
+fn scale_fan_outline() -> [Vec2; SCALE_FAN_ARC_POINTS + 1] {
+    let mut outline = [Vec2::new(0.5, 1.0); SCALE_FAN_ARC_POINTS + 1];
+    let max_angle = SCALE_FAN_ANGLE.to_radians();
+    for (i, point) in outline.iter_mut().skip(1).enumerate() {
+        let angle = f32::lerp(max_angle, -max_angle, i as f32 / (SCALE_FAN_ARC_POINTS - 1) as f32);
+        let (sin, cos) = angle.sin_cos();
+        *point = Vec2::new(0.5 + 0.5 * sin / max_angle.sin(), 1.0 - cos);
+    }
+    outline
+}
+
+/// normal of a single leaf vertex; pivot is the point where the leaf is attached to its branch
+fn leaf_vertex_normal(settings: &TreeMeshSettings, vertex: Vec3, pivot: Vec3, flat_normal: Vec3) -> Vec3 {
+    match settings.leaves.normal_mode {
+        // the pivot lies in the plane of the card: the normals point away from a center behind it (instead of along the card)
+        LeafNormalMode::SphericalBranch => {
+            let radial = vertex - pivot;
+            (radial + flat_normal * radial.length() * SPHERICAL_BRANCH_DEPTH).try_normalize().unwrap_or(flat_normal)
+        },
+        // crown based normals are calculated after all leaves are generated
+        LeafNormalMode::Flat | LeafNormalMode::SphericalCrown | LeafNormalMode::Blended(_) => flat_normal,
+    }
+}
+
+/// append leaf geometry to the leaves mesh; triangles are indices relative to the given vertices
+/// `pivot` is the point the leaf grows out of in the growth animation
+fn push_leaf_geometry(leaves_attributes: &mut MeshAttributes, vertices: &[Vec3], normals: &[Vec3], uvs: &[[f32;2]], triangles: &[usize], pivot: Vec3) {
+    let indices_start: usize = leaves_attributes.positions.len();
+
+    leaves_attributes.growth_pivots.extend(std::iter::repeat_n(pivot.to_array(), vertices.len()));
+    leaves_attributes.leaf_randoms.extend(std::iter::repeat_n(hash_index(leaves_attributes.leaf_count, leaves_attributes.leaf_seed), vertices.len()));
+    leaves_attributes.leaf_count += 1;
+    leaves_attributes.positions.extend(vertices.iter().map(|v| v.to_array()));
+    leaves_attributes.normals.extend(normals.iter().map(|n| n.to_array()));
+    leaves_attributes.uvs.extend_from_slice(uvs);
+    #[cfg(not(feature = "u32_indices"))]
+    leaves_attributes.indices.extend(triangles.iter().map(|i| (indices_start + i) as u16));
+    #[cfg(feature = "u32_indices")]
+    leaves_attributes.indices.extend(triangles.iter().map(|i| (indices_start + i) as u32));
+}
+
+/// replace/blend the flat leaf normals with normals pointing away from the center of the crown
+fn apply_crown_leaf_normals(settings: &TreeMeshSettings, leaves_attributes: &mut MeshAttributes) {
+    let crown_factor = match settings.leaves.normal_mode {
+        LeafNormalMode::SphericalCrown => 1.0,
+        LeafNormalMode::Blended(factor) => factor.clamp(0.0, 1.0),
+        LeafNormalMode::Flat | LeafNormalMode::SphericalBranch => return,
+    };
+    if leaves_attributes.positions.is_empty() {
+        return;
+    }
+
+    // center of the bounding box of all leaves
+    let (min, max) = leaves_attributes.positions.iter().fold(
+        (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+        |(min, max), p| (min.min(Vec3::from_array(*p)), max.max(Vec3::from_array(*p)))
+    );
+    let crown_center = (min + max) / 2.0;
+
+    for (position, normal) in leaves_attributes.positions.iter().zip(leaves_attributes.normals.iter_mut()) {
+        let flat_normal = Vec3::from_array(*normal);
+        let crown_normal = (Vec3::from_array(*position) - crown_center).try_normalize().unwrap_or(flat_normal);
+        // cards are visible from both sides: blend with the side facing outwards
+        let flat_normal = if flat_normal.dot(crown_normal) < 0.0 { -flat_normal } else { flat_normal };
+        *normal = flat_normal.lerp(crown_normal, crown_factor).try_normalize().unwrap_or(crown_normal).to_array();
+    }
+}
+
+/// color as linear rgba (to blend and multiply colors)
+fn linear_color(color: Color) -> Vec4 {
+    Vec4::from_array(LinearRgba::from(color).to_f32_array())
+}
+
+/// vertex colors of the branches and leaves (see VertexColorParams); the baked ambient occlusion replaces the approximation
+fn vertex_colors(settings: &TreeMeshSettings, ambient_occlusion: Option<&(Vec<f32>, Vec<f32>)>, branches_attributes: &mut MeshAttributes, leaves_attributes: &MeshAttributes) -> (Vec<[f32; 4]>, Vec<[f32; 4]>) {
+    let params = &settings.vertex_colors;
+    let bounds = |positions: &[[f32; 3]]| positions.iter().fold(
+        (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+        |(min, max), p| (min.min(Vec3::from_array(*p)), max.max(Vec3::from_array(*p)))
+    );
+    // vertices without a level (i.e. the root flare) belong to the trunk
+    branches_attributes.levels.resize(branches_attributes.positions.len(), 0);
+
+    // height of the whole tree and the crown (the leaves or the branches of trees without leaves)
+    let (tree_min, tree_max) = bounds(&[&branches_attributes.positions[..], &leaves_attributes.positions[..]].concat());
+    let (crown_min, crown_max) = bounds(if leaves_attributes.positions.is_empty() { &branches_attributes.positions } else { &leaves_attributes.positions });
+    let crown_center = (crown_min + crown_max) / 2.0;
+    let crown_extent = ((crown_max - crown_min) / 2.0).max(Vec3::splat(f32::EPSILON));
+    let gradient = params.height_gradient.map(linear_color);
+
+    // shared by both meshes: the height gradient and the ambient occlusion approximation
+    let shade = |position: [f32; 3], baked_occlusion: Option<f32>| {
+        let position = Vec3::from_array(position);
+        let height = ((position.y - tree_min.y) / (tree_max.y - tree_min.y).max(f32::EPSILON)).clamp(0.0, 1.0);
+        let occlusion = baked_occlusion.unwrap_or_else(|| {
+            // deep inside of the crown and close to the trunk
+            let depth = 1.0 - ((position - crown_center) / crown_extent).length().min(1.0);
+            let trunk_proximity = 1.0 - (position.xz().length() / crown_extent.x.max(crown_extent.z)).min(1.0);
+            1.0 - params.occlusion.clamp(0.0, 1.0) * (depth + trunk_proximity) / 2.0
+        });
+        gradient[0].lerp(gradient[1], height) * Vec4::new(occlusion, occlusion, occlusion, 1.0)
+    };
+    let baked_occlusion = |occlusion: Option<&Vec<f32>>, index: usize| occlusion.and_then(|occlusion| occlusion.get(index).copied());
+
+    let bark_color = linear_color(params.bark_color);
+    let level_tints = params.level_tint.map(linear_color);
+    let branches_colors = branches_attributes.positions.iter().zip(&branches_attributes.levels).enumerate()
+        .map(|(index, (position, level))| {
+            let occlusion = baked_occlusion(ambient_occlusion.map(|(branches_occlusion, _)| branches_occlusion), index);
+            (bark_color * level_tints[(*level).min(level_tints.len() - 1)] * shade(*position, occlusion)).to_array()
+        })
+        .collect();
+
+    let leaves_colors = leaves_attributes.positions.iter().zip(&leaves_attributes.leaf_randoms).enumerate()
+        .map(|(index, (position, random))| {
+            let leaf_color = linear_color(Hsla::from(params.leaf_color).rotate_hue(((random * 2.0 - 1.0) * params.leaf_hue_variation).rem_euclid(360.0)).into());
+            let occlusion = baked_occlusion(ambient_occlusion.map(|(_, leaves_occlusion)| leaves_occlusion), index);
+            (leaf_color * shade(*position, occlusion)).to_array()
+        })
+        .collect();
+
+    (branches_colors, leaves_colors)
+}
+
+/// snow and moss masks of the branches and leaves (see SurfaceMaskParams)
+fn surface_masks(settings: &TreeMeshSettings, branches_attributes: &MeshAttributes, leaves_attributes: &MeshAttributes) -> (Vec<[f32; 2]>, Vec<[f32; 2]>) {
+    let params = &settings.surface_masks;
+    let surfaces = [branches_attributes, leaves_attributes].map(|attributes| OcclusionSurface {
+        positions: &attributes.positions,
+        normals: &attributes.normals,
+        indices: &attributes.indices,
+    });
+    let mut exposure = bake_exposure(&surfaces, SNOW_SHELTER_CELL_SIZE, params.snow_shelter);
+    let leaves_exposure = exposure.pop().unwrap_or_default();
+    let branches_exposure = exposure.pop().unwrap_or_default();
+
+    let (min_height, max_height) = branches_attributes.positions.iter().chain(&leaves_attributes.positions)
+        .fold((f32::MAX, f32::MIN), |(min, max), p| (min.min(p[1]), max.max(p[1])));
+    let min_up = params.snow_max_angle.clamp(0.0, 90.0).to_radians().cos();
+    let snow = |up: f32, exposure: f32| ((up - min_up) / (1.0 - min_up).max(f32::EPSILON)).clamp(0.0, 1.0) * exposure;
+
+    let moss_direction = params.moss_direction.xz().normalize_or_zero();
+    let spread = params.moss_spread.clamp(0.0, 1.0);
+    let moss = |position: [f32; 3], normal: Vec3, exposure: f32| {
+        // 0.0 = facing away from the moss direction, 1.0 = facing it
+        let facing = (normal.xz().normalize_or_zero().dot(moss_direction) + 1.0) / 2.0;
+        let side = if spread > f32::EPSILON { ((facing - (1.0 - spread)) / spread).clamp(0.0, 1.0) } else if facing >= 1.0 - f32::EPSILON { 1.0 } else { 0.0 };
+        // moist close to the ground or in the shade
+        let height = (position[1] - min_height) / (max_height - min_height).max(f32::EPSILON);
+        let low = 1.0 - (height / params.moss_height.max(f32::EPSILON)).clamp(0.0, 1.0);
+        side * low.max(1.0 - exposure)
+    };
+
+    let branches_masks = branches_attributes.positions.iter().zip(&branches_attributes.normals).zip(&branches_exposure)
+        .map(|((position, normal), exposure)| {
+            let normal = Vec3::from_array(*normal);
+            [snow(normal.y, *exposure), moss(*position, normal, *exposure)]
+        })
+        .collect();
+    // leaf cards are visible from both sides (snow settles on the side facing up)
+    let leaves_masks = leaves_attributes.normals.iter().zip(&leaves_exposure)
+        .map(|(normal, exposure)| [snow(normal[1].abs(), *exposure), 0.0])
+        .collect();
+
+    (branches_masks, leaves_masks)
+}
+
+/// pick a weighted random variant of the atlas and return its uv-rect
+fn pick_leaf_atlas_variant(atlas: &LeafAtlas, rng: &mut Rng) -> Rect {
+    let variant_count = atlas.variant_count();
+    let total_weight: f32 = (0..variant_count).map(|i| atlas.variant_weight(i)).sum();
+    if variant_count == 0 || total_weight <= 0.0 {
+        return Rect::new(0.0, 0.0, 1.0, 1.0);
+    }
+
+    let mut target = rng.f32() * total_weight;
+    for i in 0..variant_count {
+        target -= atlas.variant_weight(i);
+        if target < 0.0 {
+            return atlas.variant_rect(i);
+        }
+    }
+    // floating point inaccuracies: use the last variant with a weight
+    let last_index = (0..variant_count).rev().find(|i| atlas.variant_weight(*i) > 0.0).unwrap_or(0);
+    atlas.variant_rect(last_index)
 }
\ No newline at end of file
@@ -0,0 +1,52 @@
+use bevy::prelude::*;
+
+/// hash of an integer lattice point (with a seed) to 0..1
+fn hash(x: i32, y: i32, z: i32, seed: u32) -> f32 {
+    let mut h = seed
+        ^ (x as u32).wrapping_mul(0x8da6_b343)
+        ^ (y as u32).wrapping_mul(0xd816_3841)
+        ^ (z as u32).wrapping_mul(0xcb1a_b31f);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x2c1b_3c6d);
+    h ^= h >> 12;
+    h = h.wrapping_mul(0x297a_2d39);
+    h ^= h >> 15;
+    (h & 0x00ff_ffff) as f32 / 0x00ff_ffff as f32
+}
+
+/// hash of an index (with a seed) to 0..1
+pub(crate) fn hash_index(index: u32, seed: u32) -> f32 {
+    hash(index as i32, 0, 0, seed)
+}
+
+/// smooth (quintic) interpolation between the lattice points
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+/// seeded 3d value noise (0..1)
+pub(crate) fn value_noise(p: Vec3, seed: u32) -> f32 {
+    let cell = p.floor();
+    let (x, y, z) = (cell.x as i32, cell.y as i32, cell.z as i32);
+    let f = p - cell;
+    let (u, v, w) = (fade(f.x), fade(f.y), fade(f.z));
+
+    let lerp_x = |y: i32, z: i32| f32::lerp(hash(x, y, z, seed), hash(x + 1, y, z, seed), u);
+    let lerp_y = |z: i32| f32::lerp(lerp_x(y, z), lerp_x(y + 1, z), v);
+    f32::lerp(lerp_y(z), lerp_y(z + 1), w)
+}
+
+/// fractal sum of value noise (0..1) with the given amount of octaves (each octave doubles the frequency and halves the amplitude)
+pub(crate) fn fbm(p: Vec3, seed: u32, octaves: u8) -> f32 {
+    let mut sum = 0.0;
+    let mut amplitude = 1.0;
+    let mut weights = 0.0;
+    let mut frequency = 1.0;
+    for octave in 0..octaves.max(1) {
+        sum += amplitude * value_noise(p * frequency, seed.wrapping_add(octave as u32));
+        weights += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    sum / weights
+}
@@ -0,0 +1,83 @@
+use std::{fmt, sync::Arc};
+
+use bevy::prelude::*;
+
+/// Something the branches of a tree grow around (i.e. walls, rocks or neighbouring trees)
+///
+/// All obstacles are given in the local space of the tree (the trunk starts at Vec3::ZERO).
+#[derive(Clone)]
+pub enum Obstacle {
+    /// axis aligned box
+    Aabb { min: Vec3, max: Vec3 },
+    Sphere { center: Vec3, radius: f32 },
+    /// line segment from start to end with a radius
+    Capsule { start: Vec3, end: Vec3, radius: f32 },
+    /// custom signed distance function (negative inside the obstacle)
+    Sdf(Arc<dyn Fn(Vec3) -> f32 + Send + Sync>),
+}
+
+impl Obstacle {
+    /// signed distance from the given point to the surface of the obstacle (negative inside)
+    pub fn distance(&self, point: Vec3) -> f32 {
+        match self {
+            Obstacle::Aabb { min, max } => {
+                let center = (*min + *max) / 2.0;
+                let half_size = (*max - *min).abs() / 2.0;
+                let q = (point - center).abs() - half_size;
+                q.max(Vec3::ZERO).length() + q.max_element().min(0.0)
+            },
+            Obstacle::Sphere { center, radius } => point.distance(*center) - radius,
+            Obstacle::Capsule { start, end, radius } => {
+                let segment = *end - *start;
+                let t = ((point - *start).dot(segment) / segment.length_squared().max(f32::EPSILON)).clamp(0.0, 1.0);
+                point.distance(*start + segment * t) - radius
+            },
+            Obstacle::Sdf(sdf) => sdf(point),
+        }
+    }
+}
+
+impl fmt::Debug for Obstacle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Obstacle::Aabb { min, max } => f.debug_struct("Aabb").field("min", min).field("max", max).finish(),
+            Obstacle::Sphere { center, radius } => f.debug_struct("Sphere").field("center", center).field("radius", radius).finish(),
+            Obstacle::Capsule { start, end, radius } => f.debug_struct("Capsule").field("start", start).field("end", end).field("radius", radius).finish(),
+            Obstacle::Sdf(_) => f.write_str("Sdf"),
+        }
+    }
+}
+
+/// distance to the closest obstacle (f32::MAX without obstacles)
+pub(crate) fn distance_to_obstacles(obstacles: &[Obstacle], point: Vec3) -> f32 {
+    obstacles.iter().map(|obstacle| obstacle.distance(point)).fold(f32::MAX, f32::min)
+}
+
+/// whether a ray hits an obstacle within the given distance (sphere tracing)
+pub(crate) fn ray_hits_obstacles(obstacles: &[Obstacle], origin: Vec3, direction: Vec3, max_distance: f32) -> bool {
+    const MIN_STEP: f32 = 0.05;
+    const MAX_STEPS: usize = 64;
+    let mut travelled = 0.0;
+    for _ in 0..MAX_STEPS {
+        if travelled > max_distance {
+            break;
+        }
+        let distance = distance_to_obstacles(obstacles, origin + direction * travelled);
+        if distance <= 0.0 {
+            return true;
+        }
+        travelled += distance.max(MIN_STEP);
+    }
+    false
+}
+
+/// direction pointing away from the closest obstacle (central differences of the distance)
+pub(crate) fn obstacle_normal(obstacles: &[Obstacle], point: Vec3) -> Vec3 {
+    const EPSILON: f32 = 0.01;
+    let distance = |offset: Vec3| distance_to_obstacles(obstacles, point + offset);
+    Vec3::new(
+        distance(Vec3::X * EPSILON) - distance(Vec3::NEG_X * EPSILON),
+        distance(Vec3::Y * EPSILON) - distance(Vec3::NEG_Y * EPSILON),
+        distance(Vec3::Z * EPSILON) - distance(Vec3::NEG_Z * EPSILON),
+    ).normalize_or_zero()
+}
@@ -0,0 +1,237 @@
+use std::{collections::HashMap, f32::consts::PI};
+
+use bevy::prelude::*;
+
+use crate::settings::AmbientOcclusionParams;
+
+/// upper limit of the cells of the density grid per axis (larger trees get larger cells)
+const MAX_CELLS_PER_AXIS: f32 = 64.0;
+/// light is blocked by a randomly oriented leaf with half of its area (on average)
+const PROJECTED_AREA_FACTOR: f32 = 0.5;
+/// light reaching a vertex from the sky near the horizon (0.0) and above it (1.0)
+const HORIZON_LIGHT: f32 = 0.5;
+
+/// a triangle mesh (positions, normals and indices) that blocks the light and receives ambient occlusion
+pub(crate) struct OcclusionSurface<'a, I> {
+    pub positions: &'a [[f32; 3]],
+    pub normals: &'a [[f32; 3]],
+    pub indices: &'a [I],
+}
+
+/// light blocking area of the leaves and branches per volume which light rays march through
+trait DensityField {
+    /// corner of the cell (0, 0, 0)
+    fn origin(&self) -> Vec3;
+    fn cell_size(&self) -> f32;
+    fn sample(&self, position: Vec3) -> f32;
+
+    /// distance along a ray from a position to where it leaves the cell of the position
+    fn cell_exit(&self, position: Vec3, direction: Vec3) -> f32 {
+        let local = (position - self.origin()) / self.cell_size();
+        let cell = local.floor();
+        let exit = Vec3::select(direction.cmpgt(Vec3::ZERO), cell + Vec3::ONE, cell);
+        (0..3).filter(|axis| direction[*axis] != 0.0)
+            .map(|axis| (exit[axis] - local[axis]) / direction[axis])
+            .fold(f32::MAX, f32::min) * self.cell_size()
+    }
+
+    /// light reaching a position along a ray of the given amount of cells (Beer-Lambert); starts where the ray leaves the cell of the position to skip the surface itself
+    fn transmittance(&self, position: Vec3, direction: Vec3, steps: usize, extinction: f32) -> f32 {
+        let start = self.cell_exit(position, direction);
+        let optical_depth: f32 = (0..steps).map(|i| self.sample(position + direction * (start + self.cell_size() * (i as f32 + 0.5)))).sum();
+        (-optical_depth * extinction * self.cell_size()).exp()
+    }
+}
+
+/// surface area of the leaves and branches per volume (leaf area density) in a regular grid
+struct DensityGrid {
+    min: Vec3,
+    cell_size: f32,
+    cells: UVec3,
+    density: Vec<f32>,
+}
+
+impl DensityGrid {
+    fn new(min: Vec3, max: Vec3, cell_size: f32) -> Self {
+        let extent = (max - min).max(Vec3::splat(f32::EPSILON));
+        let cell_size = cell_size.max(extent.max_element() / MAX_CELLS_PER_AXIS).max(0.01);
+        let cells = (extent / cell_size).ceil().as_uvec3().max(UVec3::ONE);
+        Self {
+            min,
+            cell_size,
+            cells,
+            density: vec![0.0; (cells.x * cells.y * cells.z) as usize],
+        }
+    }
+
+    fn cell(&self, position: Vec3) -> Option<usize> {
+        let cell = ((position - self.min) / self.cell_size).floor();
+        if cell.min_element() < 0.0 || cell.cmpge(self.cells.as_vec3()).any() {
+            return None;
+        }
+        let cell = cell.as_uvec3();
+        Some(((cell.z * self.cells.y + cell.y) * self.cells.x + cell.x) as usize)
+    }
+
+    /// add the area of each triangle to the cell of its center
+    fn add_triangles<I: Copy + Into<u32>>(&mut self, surface: &OcclusionSurface<I>) {
+        let cell_volume = self.cell_size.powi(3);
+        for triangle in surface.indices.chunks_exact(3) {
+            let [a, b, c] = [triangle[0], triangle[1], triangle[2]].map(|index| Vec3::from_array(surface.positions[index.into() as usize]));
+            let area = (b - a).cross(c - a).length() / 2.0;
+            if let Some(cell) = self.cell((a + b + c) / 3.0) {
+                self.density[cell] += area / cell_volume;
+            }
+        }
+    }
+}
+
+impl DensityField for DensityGrid {
+    fn origin(&self) -> Vec3 {
+        self.min
+    }
+
+    fn cell_size(&self) -> f32 {
+        self.cell_size
+    }
+
+    fn sample(&self, position: Vec3) -> f32 {
+        self.cell(position).map_or(0.0, |cell| self.density[cell])
+    }
+}
+
+/// Area blocking the light from a single direction per volume, like the density grid but without bounds: filled while the tree is generated (for the shade of the parts generated so far)
+pub(crate) struct ShadeGrid {
+    cell_size: f32,
+    /// direction towards the light (normalized)
+    light: Vec3,
+    density: HashMap<IVec3, f32>,
+}
+
+impl ShadeGrid {
+    pub fn new(cell_size: f32, light: Vec3) -> Self {
+        Self {
+            cell_size: cell_size.max(0.01),
+            light: light.normalize_or(Vec3::Y),
+            density: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, position: Vec3, projected_area: f32) {
+        let cell = (position / self.cell_size).floor().as_ivec3();
+        *self.density.entry(cell).or_default() += projected_area / self.cell_size.powi(3);
+    }
+
+    /// add the area of each triangle facing the light (either side) to the cell of its center
+    pub fn add_triangles<I: Copy + Into<u32>>(&mut self, surface: &OcclusionSurface<I>) {
+        for triangle in surface.indices.chunks_exact(3) {
+            let [a, b, c] = [triangle[0], triangle[1], triangle[2]].map(|index| Vec3::from_array(surface.positions[index.into() as usize]));
+            self.add((a + b + c) / 3.0, (b - a).cross(c - a).dot(self.light).abs() / 2.0);
+        }
+    }
+
+    /// add the silhouette of a tube through the given rings (center and radius) seen from the light, split into pieces of the cell size
+    /// 
+    /// a tube pointing at the light blocks (almost) nothing, so a branch does not shade the branches growing along its own axis
+    pub fn add_tube(&mut self, rings: &[(Vec3, f32)]) {
+        for ring in rings.windows(2) {
+            let [(start, start_radius), (end, end_radius)] = [ring[0], ring[1]];
+            let pieces = (start.distance(end) / self.cell_size).ceil().max(1.0);
+            let silhouette = (end - start).cross(self.light).length() / pieces;
+            for piece in 0..pieces as usize {
+                let t = (piece as f32 + 0.5) / pieces;
+                self.add(start.lerp(end, t), 2.0 * f32::lerp(start_radius, end_radius, t) * silhouette);
+            }
+        }
+    }
+
+    /// part of the light blocked on the way from a position towards the light up to the given distance (0 = in the light .. 1 = completely shaded)
+    pub fn shade(&self, position: Vec3, max_distance: f32) -> f32 {
+        let steps = (max_distance.max(0.0) / self.cell_size).ceil() as usize;
+        1.0 - self.transmittance(position, self.light, steps, 1.0)
+    }
+}
+
+impl DensityField for ShadeGrid {
+    fn origin(&self) -> Vec3 {
+        Vec3::ZERO
+    }
+
+    fn cell_size(&self) -> f32 {
+        self.cell_size
+    }
+
+    fn sample(&self, position: Vec3) -> f32 {
+        self.density.get(&(position / self.cell_size).floor().as_ivec3()).copied().unwrap_or(0.0)
+    }
+}
+
+/// grid of the density of all surfaces (none if there are no vertices)
+fn density_grid<I: Copy + Into<u32>>(surfaces: &[OcclusionSurface<I>], cell_size: f32) -> Option<DensityGrid> {
+    let (min, max) = surfaces.iter().flat_map(|surface| surface.positions).fold(
+        (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+        |(min, max), p| (min.min(Vec3::from_array(*p)), max.max(Vec3::from_array(*p)))
+    );
+    if min.cmpgt(max).any() {
+        return None;
+    }
+
+    let mut grid = DensityGrid::new(min, max, cell_size);
+    for surface in surfaces {
+        grid.add_triangles(surface);
+    }
+    Some(grid)
+}
+
+/// Bake the ambient occlusion (0 = fully occluded, 1 = unoccluded) of all vertices of the given surfaces
+///
+/// Rays from each vertex towards the sky march through the density of all surfaces; the light along each ray decays with the leaf area it passes (Beer-Lambert).
+pub(crate) fn bake_ambient_occlusion<I: Copy + Into<u32>>(params: &AmbientOcclusionParams, surfaces: &[OcclusionSurface<I>]) -> Vec<Vec<f32>> {
+    let Some(grid) = density_grid(surfaces, params.cell_size) else {
+        return surfaces.iter().map(|_| Vec::new()).collect();
+    };
+
+    let directions = sky_directions(params.rays.max(1) as usize);
+    let steps = (params.max_distance.max(0.0) / grid.cell_size).ceil() as usize;
+    let extinction = params.density.max(0.0) * PROJECTED_AREA_FACTOR;
+    let strength = params.strength.clamp(0.0, 1.0);
+
+    surfaces.iter().map(|surface| {
+        surface.positions.iter().zip(surface.normals).map(|(position, normal)| {
+            let (position, normal) = (Vec3::from_array(*position), Vec3::from_array(*normal));
+            let (light, weights) = directions.iter().fold((0.0, 0.0), |(light, weights), direction| {
+                // light from behind the surface counts less (cards are lit from both sides)
+                let weight = (HORIZON_LIGHT + (1.0 - HORIZON_LIGHT) * direction.y) * (0.5 + 0.5 * direction.dot(normal));
+                (light + weight * grid.transmittance(position, *direction, steps, extinction), weights + weight)
+            });
+            let occlusion = if weights > 0.0 { light / weights } else { 1.0 };
+            f32::lerp(1.0, occlusion, strength)
+        }).collect()
+    }).collect()
+}
+
+/// Bake the exposure to the sky straight above (0 = fully sheltered, 1 = open sky) of all vertices of the given surfaces
+/// 
+/// Like the ambient occlusion, but with a single ray up through the whole tree (i.e. for snow sheltered by the branches and leaves above).
+pub(crate) fn bake_exposure<I: Copy + Into<u32>>(surfaces: &[OcclusionSurface<I>], cell_size: f32, density: f32) -> Vec<Vec<f32>> {
+    let Some(grid) = density_grid(surfaces, cell_size) else {
+        return surfaces.iter().map(|_| Vec::new()).collect();
+    };
+    let steps = grid.cells.y as usize;
+    let extinction = density.max(0.0) * PROJECTED_AREA_FACTOR;
+
+    surfaces.iter().map(|surface| {
+        surface.positions.iter().map(|position| grid.transmittance(Vec3::from_array(*position), Vec3::Y, steps, extinction)).collect()
+    }).collect()
+}
+
+/// evenly distributed directions on the upper hemisphere (fibonacci spiral)
+fn sky_directions(count: usize) -> Vec<Vec3> {
+    let golden_angle = PI * (3.0 - 5.0_f32.sqrt());
+    (0..count).map(|i| {
+        let y = 1.0 - (i as f32 + 0.5) / count as f32;
+        let radius = (1.0 - y * y).sqrt();
+        let angle = golden_angle * i as f32;
+        Vec3::new(angle.cos() * radius, y, angle.sin() * radius)
+    }).collect()
+}
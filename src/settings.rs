@@ -7,7 +7,7 @@ use bevy::prelude::*;
 #[cfg(feature="inspector")]
 use bevy_inspector_egui::prelude::*;
 
-use crate::enums::{LeafBillboard, TreeType};
+use crate::enums::{BarkUvMode, BranchJointMode, CrossSection, EndCap, GenerationAlgorithm, GravityMode, LeafBillboard, LeafNormalMode, TreeType};
 
 
 #[cfg(feature="inspector")]
@@ -15,8 +15,20 @@ use crate::enums::{LeafBillboard, TreeType};
 #[reflect(Resource, Component, InspectorOptions)]
 pub struct TreeMeshSettings {
     pub tree_type: TreeType,
+    pub algorithm: GenerationAlgorithm,
     pub branch: BranchParams,
     pub leaves: LeafParams,
+    pub roots: RootParams,
+    pub trunk: TrunkParams,
+    pub palm: PalmParams,
+    pub shrub: ShrubParams,
+    pub phototropism: Phototropism,
+    pub obstacles: ObstacleParams,
+    pub space_colonization: SpaceColonizationParams,
+    pub lsystem: LSystemParams,
+    pub vertex_colors: VertexColorParams,
+    pub ambient_occlusion: AmbientOcclusionParams,
+    pub surface_masks: SurfaceMaskParams,
 }
 
 
@@ -25,8 +37,20 @@ pub struct TreeMeshSettings {
 #[reflect(Resource, Component)]
 pub struct TreeMeshSettings {
     pub tree_type: TreeType,
+    pub algorithm: GenerationAlgorithm,
     pub branch: BranchParams,
     pub leaves: LeafParams,
+    pub roots: RootParams,
+    pub trunk: TrunkParams,
+    pub palm: PalmParams,
+    pub shrub: ShrubParams,
+    pub phototropism: Phototropism,
+    pub obstacles: ObstacleParams,
+    pub space_colonization: SpaceColonizationParams,
+    pub lsystem: LSystemParams,
+    pub vertex_colors: VertexColorParams,
+    pub ambient_occlusion: AmbientOcclusionParams,
+    pub surface_masks: SurfaceMaskParams,
 }
 
 
@@ -34,8 +58,20 @@ impl Default for TreeMeshSettings {
     fn default() -> Self {
         Self {       
             tree_type: TreeType::Deciduous,
+            algorithm: GenerationAlgorithm::Recursive,
             branch: BranchParams::default(),
             leaves: LeafParams::default(),
+            roots: RootParams::default(),
+            trunk: TrunkParams::default(),
+            palm: PalmParams::default(),
+            shrub: ShrubParams::default(),
+            phototropism: Phototropism::default(),
+            obstacles: ObstacleParams::default(),
+            space_colonization: SpaceColonizationParams::default(),
+            lsystem: LSystemParams::default(),
+            vertex_colors: VertexColorParams::default(),
+            ambient_occlusion: AmbientOcclusionParams::default(),
+            surface_masks: SurfaceMaskParams::default(),
         }
     }
 }
@@ -110,6 +146,304 @@ impl From<BranchRecursionLevel> for f32 {
 }
 
 
+/**
+ * Droop of the branches: each section bends down proportional to the length of the branch it carries (its load) and its flexibility.
+ * Thin and long branches sag, thick ones don't. In contrast to a BranchForce pointing down, the tips of weeping branches hang down vertically (draped instead of bent).
+ */
+#[derive(Reflect, Debug, Clone, PartialEq)]
+pub struct GravityParams {
+    /// no gravity, sagging or weeping branches
+    pub mode: GravityMode,
+    /// overall strength of the gravity
+    pub strength: f32,
+    /// flexibility per level (0 = stiff)
+    pub flexibility: [f32; 4],
+    /// only used for GravityMode::Weeping: where the branches start to hang down (0..1 along each branch)
+    pub weeping_start: f32,
+}
+
+impl Default for GravityParams {
+    fn default() -> Self {
+        Self {
+            mode: GravityMode::None,
+            strength: 1.0,
+            flexibility: [0.0, 0.3, 0.6, 1.0],
+            weeping_start: 0.3,
+        }
+    }
+}
+
+/**
+ * Growth towards the light (separate from BranchForce; the light does not care about the radius of a branch).
+ * Branches bend towards the light and leaf cards turn their faces to it.
+ * Shaded child branches grow shorter and sparser (only GenerationAlgorithm::Recursive): the light is blocked by the obstacles and by the branches and leaves generated before them.
+ */
+#[derive(Reflect, Debug, Clone, PartialEq)]
+pub struct Phototropism {
+    /// direction towards the light (i.e. the sun); value will be normalized internally
+    pub direction: Vec3,
+    /// how fast the branches bend towards the light per level (0 = not at all)
+    pub strength: [f32; 4],
+    /// how much the leaf cards are turned towards the light (0..1)
+    pub leaf_strength: f32,
+    /// reduction of the length of completely shaded branches (0..1)
+    pub shade_length_reduction: f32,
+    /// probability that a completely shaded branch is not grown at all (0..1)
+    pub shade_density_reduction: f32,
+}
+
+impl Default for Phototropism {
+    fn default() -> Self {
+        Self {
+            direction: Vec3::new(0.3, 1.0, 0.2),
+            strength: [0.0; 4],
+            leaf_strength: 0.0,
+            shade_length_reduction: 0.0,
+            shade_density_reduction: 0.0,
+        }
+    }
+}
+
+/**
+ * How branches react to obstacles (see `TreeGenerationContext` and the `TreeObstacles` component).
+ * Each section checks the position of the next section: closer than the margin the branch bends away, inside of an obstacle the branch stops.
+ */
+#[derive(Reflect, Debug, Clone, PartialEq)]
+pub struct ObstacleParams {
+    /// distance to obstacles at which branches start to bend away
+    pub margin: f32,
+    /// how strong branches bend away per section (0..1)
+    pub avoidance: f32,
+    /// branches stop growing instead of entering an obstacle
+    pub stop_inside: bool,
+}
+
+impl Default for ObstacleParams {
+    fn default() -> Self {
+        Self {
+            margin: 0.5,
+            avoidance: 0.5,
+            stop_inside: true,
+        }
+    }
+}
+
+/**
+ * Vertex colors (Mesh::ATTRIBUTE_COLOR) of both meshes, i.e. for a stylized look shaded entirely from vertex colors.
+ * The colors are multiplied with the materials (a white base color shows them unchanged).
+ */
+#[derive(Reflect, Debug, Clone, PartialEq)]
+pub struct VertexColorParams {
+    /// generate the vertex colors (all other fields are ignored if disabled)
+    pub enabled: bool,
+    pub bark_color: Color,
+    pub leaf_color: Color,
+    /// tint of the branches per level (multiplied with the bark color); the surface roots get the tint of the trunk (level 0)
+    pub level_tint: [Color; 4],
+    /// strength of the ambient occlusion approximation (0..1): darker deep inside of the crown and close to the trunk
+    /// 
+    /// replaced by the baked ambient occlusion if it is enabled (see AmbientOcclusionParams)
+    pub occlusion: f32,
+    /// random hue shift of each leaf in degrees (in both directions)
+    pub leaf_hue_variation: f32,
+    /// colors multiplied at the bottom and at the top of the tree (blended by the height)
+    pub height_gradient: [Color; 2],
+}
+
+impl Default for VertexColorParams {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bark_color: Color::srgb(0.45, 0.35, 0.25),
+            leaf_color: Color::srgb(0.3, 0.6, 0.2),
+            level_tint: [Color::WHITE, Color::srgb(0.95, 0.92, 0.88), Color::srgb(0.9, 0.86, 0.8), Color::srgb(0.85, 0.8, 0.72)],
+            occlusion: 0.5,
+            leaf_hue_variation: 10.0,
+            height_gradient: [Color::srgb(0.8, 0.8, 0.8), Color::WHITE],
+        }
+    }
+}
+
+/**
+ * Ambient occlusion baked into both meshes (ATTRIBUTE_AMBIENT_OCCLUSION and the vertex colors if enabled).
+ * Rays from each vertex towards the sky march through a grid of the leaf and bark area around them: inner leaves and branches get darker.
+ */
+#[derive(Reflect, Debug, Clone, PartialEq)]
+pub struct AmbientOcclusionParams {
+    /// bake the ambient occlusion (all other fields are ignored if disabled)
+    pub enabled: bool,
+    /// amount of rays per vertex
+    pub rays: u32,
+    /// how far the rays march through the tree in meters
+    pub max_distance: f32,
+    /// size of the cells of the density grid in meters (smaller = more detail, but slower; large trees use larger cells)
+    pub cell_size: f32,
+    /// multiplier of the leaf and bark area blocking the light (higher = darker inside of the crown)
+    pub density: f32,
+    /// blend between no occlusion (0.0) and the baked occlusion (1.0)
+    pub strength: f32,
+}
+
+impl Default for AmbientOcclusionParams {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rays: 12,
+            max_distance: 3.0,
+            cell_size: 0.25,
+            density: 0.5,
+            strength: 1.0,
+        }
+    }
+}
+
+/**
+ * Masks for materials to blend snow and moss variants (ATTRIBUTE_SURFACE_MASKS of both meshes; x: snow, y: moss).
+ * Snow settles on surfaces facing up that are not sheltered by the branches and leaves above them.
+ * Moss grows on the bark facing the moss direction, close to the ground or in the shade (the leaves get no moss).
+ */
+#[derive(Reflect, Debug, Clone, PartialEq)]
+pub struct SurfaceMaskParams {
+    /// generate the masks (all other fields are ignored if disabled)
+    pub enabled: bool,
+    /// surfaces tilted more than this angle from the up vector get no snow (in degrees)
+    pub snow_max_angle: f32,
+    /// how much the branches and leaves above shelter the surfaces below from snow (0.0 = no shelter)
+    pub snow_shelter: f32,
+    /// direction the moss grows towards (i.e. north; in the local space of the tree)
+    pub moss_direction: Vec3,
+    /// how far the moss spreads around the branches from the side facing the moss direction (0..1; 1.0 = all around)
+    pub moss_spread: f32,
+    /// height relative to the tree (0..1) up to which the moss grows on sunny bark (more moisture close to the ground)
+    pub moss_height: f32,
+}
+
+impl Default for SurfaceMaskParams {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            snow_max_angle: 70.0,
+            snow_shelter: 1.0,
+            moss_direction: Vec3::NEG_Z,
+            moss_spread: 0.5,
+            moss_height: 0.4,
+        }
+    }
+}
+
+/**
+ * Only used for GenerationAlgorithm::SpaceColonization (the counts, lengths and angles of BranchParams are ignored).
+ * The trunk grows straight up until it reaches the crown (at most 10000 steps, otherwise the generation fails); then all branches grow step by step towards the attraction points closest to them.
+ * The radii follow the pipe model starting at BranchParams::trunk_base_radius; segments, cross-sections and bark of BranchParams are used per level.
+ * Leaves (see LeafParams) are placed along every branch except the trunk.
+ */
+#[derive(Reflect, Debug, Clone, PartialEq)]
+pub struct SpaceColonizationParams {
+    /// amount of attraction points inside of the crown (more points = denser crown)
+    pub attraction_points: u32,
+    /// height of the center of the crown
+    pub crown_height: f32,
+    /// radii of the ellipsoid of the crown
+    pub crown_size: Vec3,
+    /// attraction points further away from a branch do not influence its growth
+    pub influence_distance: f32,
+    /// attraction points closer to a branch are reached (and removed)
+    pub kill_distance: f32,
+    /// length of a single growth step (distance between the rings of the branches)
+    pub step_length: f32,
+    /// maximum amount of growth steps
+    pub max_iterations: u32,
+    /// random deviation of the growth direction (0..1)
+    pub jitter: f32,
+    /// exponent of the pipe model (2.0 = the cross-section areas of the children sum up to the parent; larger = thinner trunks)
+    pub radius_exponent: f32,
+}
+
+impl Default for SpaceColonizationParams {
+    fn default() -> Self {
+        Self {
+            attraction_points: 400,
+            crown_height: 6.0,
+            crown_size: Vec3::new(3.5, 3.0, 3.5),
+            influence_distance: 2.5,
+            kill_distance: 0.6,
+            step_length: 0.4,
+            max_iterations: 100,
+            jitter: 0.1,
+            radius_exponent: 2.5,
+        }
+    }
+}
+
+/**
+ * Only used for GenerationAlgorithm::LSystem (the counts, lengths and angles of BranchParams are ignored).
+ * The axiom is rewritten `iterations` times by the rules; the result is drawn by a turtle heading along its y-axis:
+ * `F(l)` draw forward, `f(l)` move forward, `+(a)` `-(a)` turn, `&(a)` `^(a)` pitch, `\(a)` `/(a)` roll, `|` turn around,
+ * `[` `]` push/pop the turtle, `!(r)` set the radius (without parameter: multiply by width_decay), `L` leaf (see LeafParams).
+ * Parameters are optional (defaults: step_length, angle); other symbols are only used by the rules.
+ * The turtle starts with BranchParams::trunk_base_radius; segments, cross-sections, bark and end caps of BranchParams are used per bracket level.
+ */
+#[derive(Reflect, Debug, Clone, PartialEq)]
+pub struct LSystemParams {
+    /// start string, i.e. "F(2)A(1)"
+    pub axiom: String,
+    pub rules: Vec<LSystemRule>,
+    /// amount of rewriting steps (generation fails if the string grows beyond 100000 modules)
+    pub iterations: u8,
+    /// length of F and f without parameter
+    pub step_length: f32,
+    /// angle of the rotations without parameter in degrees
+    pub angle: f32,
+    /// factor of the radius for ! without parameter
+    pub width_decay: f32,
+}
+
+impl Default for LSystemParams {
+    fn default() -> Self {
+        Self {
+            axiom: "F(1.5)A(1.2)".to_string(),
+            rules: vec![
+                LSystemRule::new("A(l)", "l > 0.3", "!F(l)[&(35)B(l)]/(137.5)[&(35)B(l)]/(137.5)A(l*0.8)", 1.0),
+                LSystemRule::new("A(l)", "l <= 0.3", "[&L]/(120)[&L]/(120)[&L]", 1.0),
+                LSystemRule::new("B(l)", "", "!F(l*0.6)[+L]B(l*0.8)", 0.7),
+                LSystemRule::new("B(l)", "", "!F(l*0.6)[-L][+L]", 0.3),
+            ],
+            iterations: 7,
+            step_length: 1.0,
+            angle: 25.0,
+            width_decay: 0.75,
+        }
+    }
+}
+
+/**
+ * A production rule of an L-system: replaces each matching module by the successor.
+ * Parametric rules name the parameters of the predecessor (i.e. "A(l,w)") and use them in the condition and the expressions of the successor (+ - * / and brackets).
+ * If several rules match a module, one of them is picked randomly by their probability (stochastic rules; seeded by Tree::seed).
+ */
+#[derive(Reflect, Debug, Clone, PartialEq)]
+pub struct LSystemRule {
+    /// symbol with the names of its parameters, i.e. "A(l)"
+    pub predecessor: String,
+    /// comparison of two expressions, i.e. "l > 0.5" (< <= > >= == !=); empty = always
+    pub condition: String,
+    /// replacement, i.e. "F(l)[+A(l*0.7)]"
+    pub successor: String,
+    /// relative probability among all matching rules
+    pub probability: f32,
+}
+
+impl LSystemRule {
+    pub fn new(predecessor: &str, condition: &str, successor: &str, probability: f32) -> Self {
+        Self {
+            predecessor: predecessor.to_string(),
+            condition: condition.to_string(),
+            successor: successor.to_string(),
+            probability,
+        }
+    }
+}
+
 #[derive(Reflect, Debug, Clone, PartialEq)]
 pub struct BranchParams {
     /// amount of recursion for branches (0 = only trunk, no branches)
@@ -125,6 +459,9 @@ pub struct BranchParams {
     /// Control the general direction of branches
     pub force: BranchForce,
 
+    /// branches bending down under their own weight
+    pub gravity: GravityParams,
+
     /// curling/twisting per level (0=straight; 1=very crooked; values higher than 1 can work, but may create unrealistic branches)
     pub gnarliness: [f32; 4],
 
@@ -140,7 +477,7 @@ pub struct BranchParams {
 
     /// how many sections each brach has per level (along its length; more sections = more polygons)
     /// 
-    /// hint: as textures are repeated (one full uv-range per section), it can be beneficial to play around with this value to influence how often the given texture repeats on this branch to better fit the texture size
+    /// hint: as textures are repeated (one full uv-range per section; see BarkUvMode::Alternating), it can be beneficial to play around with this value to influence how often the given texture repeats on this branch to better fit the texture size
     /// 
     /// Additionnaly take a look at ['bevy::pbr::StandardMaterial::uv_transform']
     pub sections: [u8; 4],
@@ -148,6 +485,9 @@ pub struct BranchParams {
     /// how many segments each branch has per section per level (how 'round' the mesh is; more segments = more polygons)
     pub segments: [u8; 4],
 
+    /// shape of the rings per level (circular, elliptical, lobed or noisy; non-circular shapes need enough segments)
+    pub cross_section: [CrossSection; 4],
+
     /// when to start adding child branches along the length of the branch (0..1) per level
     /// The first value is ignored (the trunk is always starting at the ground level)
     pub start: [f32; 4],
@@ -157,6 +497,26 @@ pub struct BranchParams {
 
     /// twist per Level
     pub twist: [f32; 4],
+
+    /// how the v-coordinate of the bark texture is calculated (mirrored per section or continuous along the branch)
+    pub bark_uv_mode: BarkUvMode,
+
+    /// only used for BarkUvMode::Continuous: scale of the bark texture along the branch
+    /// 
+    /// 1.0 = the texture repeats once per circumference of the branch (square texels); larger values stretch the texture along the branch
+    pub bark_texture_scale: f32,
+
+    /// how child branches are connected to their parent
+    pub joint: BranchJointParams,
+
+    /// optional geometric bark detail (i.e. for close-up trunks)
+    pub displacement: BarkDisplacementParams,
+
+    /// how open ends of branches are closed (branches ending in a tip are never capped)
+    pub end_cap: EndCap,
+
+    /// height of EndCap::Dome and the splinters of EndCap::Jagged relative to the radius of the branch
+    pub end_cap_height: f32,
 }
 
 impl Default for BranchParams {
@@ -166,15 +526,260 @@ impl Default for BranchParams {
             angle: [0.0, 39.0, 39.0, 59.0],
             children: [7, 4, 10],
             force: BranchForce::default(),
+            gravity: GravityParams::default(),
             gnarliness: [-0.05, 0.20, 0.16, 0.05],
             length: [4.5, 2.9, 1.5, 0.45],
             trunk_base_radius: 0.2,
             radius_factor: [1.0, 0.5, 0.5, 0.5],
             sections: [12, 8, 6, 4],
             segments: [8, 6, 4, 3],
+            cross_section: [CrossSection::Circle; 4],
             start: [0.0, 0.32, 0.4, 0.0],
             taper: [0.95, 0.8, 0.85, 0.8],
             twist: [0.09, -0.07, 0.0, 0.0],
+            bark_uv_mode: BarkUvMode::Alternating,
+            bark_texture_scale: 1.0,
+            joint: BranchJointParams::default(),
+            displacement: BarkDisplacementParams::default(),
+            end_cap: EndCap::None,
+            end_cap_height: 0.5,
+        }
+    }
+}
+
+/**
+ * Joints between child branches and their parent.
+ * By default child branches are separate tubes starting inside their parent, which leaves visible hard intersections.
+ * A collar flares the base of the child branch and welds its first ring onto the surface of the parent.
+ */
+#[derive(Reflect, Debug, Clone, PartialEq)]
+pub struct BranchJointParams {
+    /// intersecting tubes or a blended collar
+    pub mode: BranchJointMode,
+    /// additional radius at the base of a child branch (0.5 = 50% wider at the joint); negative values are ignored
+    pub flare: f32,
+    /// length of the collar relative to the length of the child branch (0..1)
+    pub length: f32,
+}
+
+impl Default for BranchJointParams {
+    fn default() -> Self {
+        Self {
+            mode: BranchJointMode::Intersect,
+            flare: 0.6,
+            length: 0.15,
+        }
+    }
+}
+
+/**
+ * Geometric bark detail: the ring vertices are offset away from the branch axis by seeded 3d noise (ridges, furrows and knots).
+ * Normal/parallax maps can not change the silhouette of a branch, this can (at the cost of more segments for good results).
+ * The displacement is scaled down on branches thinner than trunk_base_radius.
+ */
+#[derive(Reflect, Debug, Clone, PartialEq)]
+pub struct BarkDisplacementParams {
+    /// displacement is only applied if enabled
+    pub enabled: bool,
+    /// highest level which is displaced (0 = only the trunk)
+    pub max_level: u8,
+    /// depth of the ridges and furrows in meters
+    pub strength: f32,
+    /// frequency of the ridges around the branch (ridges per meter)
+    pub ridge_frequency: f32,
+    /// how much longer the ridges are along the branch than around it
+    pub ridge_stretch: f32,
+    /// larger values create narrower ridges with wider furrows between them
+    pub ridge_sharpness: f32,
+    /// frequency of the knots (per meter)
+    pub knot_frequency: f32,
+    /// height of the knots in meters
+    pub knot_strength: f32,
+    /// how much of the bark is covered by knots (0..1)
+    pub knot_density: f32,
+}
+
+impl Default for BarkDisplacementParams {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_level: 0,
+            strength: 0.03,
+            ridge_frequency: 12.0,
+            ridge_stretch: 6.0,
+            ridge_sharpness: 1.5,
+            knot_frequency: 1.5,
+            knot_strength: 0.05,
+            knot_density: 0.15,
+        }
+    }
+}
+
+/**
+ * Multi-stem trees and forked trunks.
+ * Multiple trunks grow from a shared base (i.e. birch clumps); each trunk can split into co-dominant stems (like many old broadleaf trees).
+ */
+#[derive(Reflect, Debug, Clone, PartialEq)]
+pub struct TrunkParams {
+    /// amount of trunks growing from the base (0 is treated like 1: a single trunk)
+    pub count: u8,
+    /// only used for multiple trunks: maximum angle between a trunk and the vertical in degrees
+    pub spread_angle: f32,
+    /// random reduction of the length of each stem (0..1; only used for multiple trunks and forks)
+    pub length_variance: f32,
+    /// probability that the trunk splits into two stems at each section (0..1)
+    pub fork_probability: f32,
+    /// angle between each forked stem and its trunk in degrees
+    pub fork_angle: f32,
+    /// how often the stems may fork again (0 = no forks)
+    pub max_forks: u8,
+}
+
+impl Default for TrunkParams {
+    fn default() -> Self {
+        Self {
+            count: 1,
+            spread_angle: 20.0,
+            length_variance: 0.2,
+            fork_probability: 0.0,
+            fork_angle: 25.0,
+            max_forks: 1,
+        }
+    }
+}
+
+/**
+ * Only used for TreeType::Palm.
+ * The trunk uses the level 0 values of BranchParams (length, radius, sections, segments, taper, ...); each section is one ring of the bark texture.
+ * The fronds are part of the leaves mesh (leaflets use the leaf texture; the stem of a frond uses a thin strip of its center).
+ */
+#[derive(Reflect, Debug, Clone, PartialEq)]
+pub struct PalmParams {
+    /// total bend of the trunk in degrees (in a random direction)
+    pub bend: f32,
+    /// amount of fronds in the crown
+    pub frond_count: u8,
+    /// length of a frond
+    pub frond_length: f32,
+    /// angle of the youngest (top) fronds above the horizon in degrees; older fronds hang lower
+    pub frond_angle: f32,
+    /// how much the fronds arch down relative to their length
+    pub frond_droop: f32,
+    /// width of the stem (rachis) of a frond at its base
+    pub rachis_width: f32,
+    /// amount of leaflets per side of a frond
+    pub leaflets: u8,
+    /// length of the longest leaflets (in the middle of a frond)
+    pub leaflet_length: f32,
+    /// width of a leaflet
+    pub leaflet_width: f32,
+    /// angle of the leaflets below the plane of the frond in degrees
+    pub leaflet_droop: f32,
+}
+
+impl Default for PalmParams {
+    fn default() -> Self {
+        Self {
+            bend: 20.0,
+            frond_count: 14,
+            frond_length: 3.0,
+            frond_angle: 50.0,
+            frond_droop: 0.4,
+            rachis_width: 0.06,
+            leaflets: 24,
+            leaflet_length: 0.8,
+            leaflet_width: 0.12,
+            leaflet_droop: 25.0,
+        }
+    }
+}
+
+/**
+ * Only used for TreeType::Shrub (TrunkParams::count is ignored).
+ * Each stem is grown like the trunk of an evergreen tree with the values of BranchParams, scaled down to the size of a shrub
+ * (trunk_base_radius is the radius of a stem) and with at most `levels` levels of branches.
+ * Increase LeafParams::count for dense foliage.
+ */
+#[derive(Reflect, Debug, Clone, PartialEq)]
+pub struct ShrubParams {
+    /// amount of stems
+    pub stems: u8,
+    /// radius of the area the stems spawn from
+    pub base_radius: f32,
+    /// how far below the ground the stems start
+    pub depth: f32,
+    /// angle between the outermost stems and the vertical in degrees (stems closer to the center are more upright)
+    pub spread_angle: f32,
+    /// random reduction of the length of each stem (0..1)
+    pub length_variance: f32,
+    /// factor of the lengths and the trunk_base_radius of BranchParams for the stems and their branches
+    pub scale: f32,
+    /// maximum levels of branches of the stems (limits BranchParams::levels; many stems with many levels exceed the u16 indices)
+    pub levels: BranchRecursionLevel,
+}
+
+impl Default for ShrubParams {
+    fn default() -> Self {
+        Self {
+            stems: 8,
+            base_radius: 0.3,
+            depth: 0.1,
+            spread_angle: 40.0,
+            length_variance: 0.4,
+            scale: 0.35,
+            levels: BranchRecursionLevel::One,
+        }
+    }
+}
+
+/**
+ * The base of the trunk and optional surface roots.
+ * The trunk is flared over the first meters (with lobed cross-sections) and surface roots snake outward and downward from the base.
+ * Roots use the same gnarliness/taper model as the branches.
+ */
+#[derive(Reflect, Debug, Clone, PartialEq)]
+pub struct RootParams {
+    /// additional radius of the trunk at the ground (0.5 = 50% wider; 0.0 = no flare)
+    pub flare: f32,
+    /// height in meters over which the flare (and the lobes) fade out
+    pub flare_height: f32,
+    /// amount of lobes of the cross-section at the flared base of the trunk (0 = round)
+    pub lobes: u8,
+    /// depth of the lobes relative to the radius (0..0.9)
+    pub lobe_depth: f32,
+    /// amount of surface roots (0 = no roots)
+    pub count: u8,
+    /// average length of the surface roots
+    pub length: f32,
+    /// radius of the roots relative to trunk_base_radius
+    pub radius_factor: f32,
+    /// angle of the roots below the horizon in degrees
+    pub angle: f32,
+    /// curling/twisting of the roots (see BranchParams::gnarliness)
+    pub gnarliness: f32,
+    /// how fast the roots get thinner until the end (clamped between 0.0 and 0.9999)
+    pub taper: f32,
+    /// how many sections each root has (along its length)
+    pub sections: u8,
+    /// how many segments each root has per section (how 'round' the mesh is)
+    pub segments: u8,
+}
+
+impl Default for RootParams {
+    fn default() -> Self {
+        Self {
+            flare: 0.0,
+            flare_height: 0.6,
+            lobes: 5,
+            lobe_depth: 0.15,
+            count: 0,
+            length: 1.2,
+            radius_factor: 0.45,
+            angle: 20.0,
+            gnarliness: 0.25,
+            taper: 0.9,
+            sections: 6,
+            segments: 5,
         }
     }
 }
@@ -185,7 +790,7 @@ impl Default for BranchParams {
  */
 #[derive(Reflect, Debug, Clone, PartialEq)]
 pub struct LeafParams {
-    /// single or double/perpendicular
+    /// single or double/perpendicular cards or conifer foliage (needles, bottle brush, scale fans)
     pub leaf_billboard: LeafBillboard,
     /// angle of leaves relative to parent branch/trunk in degrees
     pub angle: f32,
@@ -198,7 +803,19 @@ pub struct LeafParams {
     /// variance of leaf sizes (negative values are ignored)
     /// 
     /// internal formula for a single leaf is: (rng(-1.0..1.0) * size_variance + 1.0) * size
-    pub size_variance: f32
+    pub size_variance: f32,
+    /// optional texture atlas for the leaf material; each leaf picks one variant (sub-rect of the texture) based on the seed
+    /// 
+    /// if set to none every leaf uses the full uv-range (0..1)
+    pub atlas: Option<LeafAtlas>,
+    /// geometry of a single leaf card (a full quad or a tight-fitting outline to reduce overdraw)
+    /// 
+    /// used by the flat leaf cards (single, double and camera facing billboards, the crossed top leaf of bottle brushes and the leaves of L-systems); needles and scale fans have their own geometry
+    pub shape: LeafShape,
+    /// how the normals of the leaves are calculated (flat cards or spherical/soft volumes)
+    pub normal_mode: LeafNormalMode,
+    /// leaf colors and leaf drop over the year (only used for trees with a TreeSeason component)
+    pub season: SeasonParams,
 }
 
 impl Default for LeafParams {
@@ -210,6 +827,210 @@ impl Default for LeafParams {
             start: 0.25,
             size: 0.25,
             size_variance: 0.2,
+            atlas: None,
+            shape: LeafShape::Quad,
+            normal_mode: LeafNormalMode::Flat,
+            season: SeasonParams::default(),
+        }
+    }
+}
+
+/**
+ * Leaf colors and leaf drop over the year (see `apply_season()`).
+ * The time of the year: 0.0 = spring, 0.25 = summer, 0.5 = autumn, 0.75 = winter; the colors are blended in between.
+ * The colors are vertex colors which are multiplied with the leaf material (a white base color shows them unchanged).
+ */
+#[derive(Reflect, Debug, Clone, PartialEq)]
+pub struct SeasonParams {
+    pub spring_color: Color,
+    pub summer_color: Color,
+    /// each leaf picks a random color between these two in autumn
+    pub autumn_colors: [Color; 2],
+    /// color of the leaves that have not fallen yet in winter
+    pub winter_color: Color,
+    /// random brightness variation of each leaf (0..1)
+    pub color_variation: f32,
+    /// random delay of the color change of each leaf (as part of the year); will be clamped between 0.0 and 0.25
+    pub timing_variation: f32,
+    /// part of the year in which the leaves fall, starting in autumn; 0.0 = evergreen (i.e. with green autumn and winter colors)
+    pub leaf_drop: f32,
+    /// falling leaves shrink (and new leaves grow at the end of winter) instead of disappearing at once
+    pub shrink: bool,
+}
+
+impl Default for SeasonParams {
+    fn default() -> Self {
+        Self {
+            spring_color: Color::srgb(0.6, 0.9, 0.35),
+            summer_color: Color::srgb(0.3, 0.6, 0.2),
+            autumn_colors: [Color::srgb(0.95, 0.75, 0.15), Color::srgb(0.8, 0.2, 0.05)],
+            winter_color: Color::srgb(0.45, 0.3, 0.15),
+            color_variation: 0.15,
+            timing_variation: 0.05,
+            leaf_drop: 0.15,
+            shrink: true,
+        }
+    }
+}
+
+/// uv corners of the default leaf quad
+const LEAF_QUAD_OUTLINE: [Vec2; 4] = [Vec2::new(0.0, 0.0), Vec2::new(0.0, 1.0), Vec2::new(1.0, 1.0), Vec2::new(1.0, 0.0)];
+
+/**
+ * Geometry of a single leaf card.
+ * Leaf textures usually contain a lot of fully transparent pixels. A tight-fitting outline reduces the overdraw (i.e. when using AlphaMode::Mask).
+ */
+#[derive(Reflect, Debug, Clone, PartialEq)]
+pub enum LeafShape {
+    /// a quad covering the full uv-range (4 vertices)
+    Quad,
+    /// an outline (convex or concave, i.e. a lobed leaf) in the uv-space of the leaf texture (0..1; relative to the atlas variant if an atlas is used)
+    /// 
+    /// the base of the leaf (where it is attached to the branch) is at v=1, the center at u=0.5
+    /// 
+    /// outlines with less than 3 points fall back to the quad; outlines without an area or with self-intersections fail the generation (LeafShapeError);
+    /// see `LeafShape::octagon_from_image_alpha()` to derive a conservative outline from a texture
+    Outline(Vec<Vec2>),
+}
+
+impl LeafShape {
+    /// the outline used for mesh generation (in uv-space of the leaf)
+    pub fn outline(&self) -> &[Vec2] {
+        match self {
+            LeafShape::Outline(outline) if outline.len() >= 3 => outline,
+            _ => &LEAF_QUAD_OUTLINE,
+        }
+    }
+
+    /// Derive a conservative outline from the alpha channel of a leaf texture on the CPU: the smallest octagon (edges 45° apart) around the opaque pixels
+    /// 
+    /// This is not the contour of the leaf: concave parts (i.e. between lobes) stay covered; use `LeafShape::Outline` with a traced contour for a tighter fit.
+    /// All pixels with an alpha value of at least `alpha_threshold` are enclosed by the outline.
+    /// If an atlas is given, the outline encloses the opaque pixels of all its variants (each variant normalized to 0..1).
+    /// 
+    /// Returns none if the pixels of the image can not be accessed (i.e. compressed formats like DDS/BCn) or if no pixel is opaque.
+    pub fn octagon_from_image_alpha(image: &Image, alpha_threshold: f32, atlas: Option<&LeafAtlas>) -> Option<Self> {
+        // the outline is the intersection of 8 half-planes (45° apart) which are pushed as far out as the opaque pixels require
+        let directions: Vec<Vec2> = (0..8).map(|i| Vec2::from_angle(i as f32 * std::f32::consts::FRAC_PI_4)).collect();
+        let mut extents = [f32::NEG_INFINITY; 8];
+
+        let size = image.size();
+        let regions: Vec<Rect> = match atlas {
+            Some(atlas) => (0..atlas.variant_count()).map(|i| atlas.variant_rect(i)).collect(),
+            None => vec![Rect::new(0.0, 0.0, 1.0, 1.0)],
+        };
+
+        for region in regions {
+            let min = (region.min * size.as_vec2()).floor().as_uvec2().min(size);
+            let max = (region.max * size.as_vec2()).ceil().as_uvec2().min(size);
+            let region_size = (max - min).as_vec2().max(Vec2::ONE);
+
+            for y in min.y..max.y {
+                for x in min.x..max.x {
+                    if image.get_color_at(x, y).ok()?.alpha() < alpha_threshold {
+                        continue;
+                    }
+                    // all corners of the pixel need to be inside the outline
+                    let pixel = (UVec2::new(x, y) - min).as_vec2();
+                    for corner in [Vec2::ZERO, Vec2::X, Vec2::Y, Vec2::ONE] {
+                        let uv = (pixel + corner) / region_size;
+                        for (extent, direction) in extents.iter_mut().zip(directions.iter()) {
+                            *extent = extent.max(uv.dot(*direction));
+                        }
+                    }
+                }
+            }
+        }
+
+        if extents[0] == f32::NEG_INFINITY {
+            return None;
+        }
+
+        // the vertices are the intersections of neighboring half-planes
+        let mut outline: Vec<Vec2> = Vec::with_capacity(8);
+        for i in 0..8 {
+            let j = (i + 1) % 8;
+            let (d1, d2) = (directions[i], directions[j]);
+            let determinant = d1.perp_dot(d2);
+            let vertex = Vec2::new(
+                extents[i] * d2.y - extents[j] * d1.y,
+                extents[j] * d1.x - extents[i] * d2.x,
+            ) / determinant;
+            let vertex = vertex.clamp(Vec2::ZERO, Vec2::ONE);
+            if outline.last().is_none_or(|last| last.distance_squared(vertex) > 1e-8) {
+                outline.push(vertex);
+            }
+        }
+        if outline.len() > 1 && outline[0].distance_squared(outline[outline.len() - 1]) <= 1e-8 {
+            outline.pop();
+        }
+
+        Some(LeafShape::Outline(outline))
+    }
+}
+
+/**
+ * Describes where the different leaf variants are placed in the leaf texture.
+ */
+#[derive(Reflect, Debug, Clone, PartialEq)]
+pub enum LeafAtlasLayout {
+    /// the texture is split into equally sized cells; variants are counted row by row starting at the top left (uv 0,0)
+    Grid { columns: u8, rows: u8 },
+    /// explicit uv-rects (0..1) for each variant
+    Rects(Vec<Rect>),
+}
+
+/**
+ * A leaf texture atlas with weighted variants (i.e. different leaf shapes, autumn colors, damaged leaves).
+ * This allows visual variety with a single leaf material.
+ */
+#[derive(Reflect, Debug, Clone, PartialEq)]
+pub struct LeafAtlas {
+    /// where the variants are located in the texture
+    pub layout: LeafAtlasLayout,
+    /// relative weight per variant (in the order of the layout; higher weight = more leaves using this variant)
+    /// 
+    /// missing entries count as 1.0; negative values are treated as 0.0
+    pub weights: Vec<f32>,
+}
+
+impl Default for LeafAtlas {
+    fn default() -> Self {
+        Self {
+            layout: LeafAtlasLayout::Grid { columns: 2, rows: 2 },
+            weights: Vec::new(),
+        }
+    }
+}
+
+impl LeafAtlas {
+    /// amount of variants described by the layout
+    pub fn variant_count(&self) -> usize {
+        match self.layout {
+            LeafAtlasLayout::Grid { columns, rows } => columns.max(1) as usize * rows.max(1) as usize,
+            LeafAtlasLayout::Rects(ref rects) => rects.len(),
+        }
+    }
+
+    /// weight of the given variant (see `weights`)
+    pub fn variant_weight(&self, index: usize) -> f32 {
+        self.weights.get(index).copied().unwrap_or(1.0).max(0.0)
+    }
+
+    /// uv-rect of the given variant; falls back to the full uv-range if the index is out of range
+    pub fn variant_rect(&self, index: usize) -> Rect {
+        match self.layout {
+            LeafAtlasLayout::Grid { columns, rows } => {
+                let columns = columns.max(1) as usize;
+                let rows = rows.max(1) as usize;
+                if index >= columns * rows {
+                    return Rect::new(0.0, 0.0, 1.0, 1.0);
+                }
+                let cell_size = Vec2::new(1.0 / columns as f32, 1.0 / rows as f32);
+                let min = Vec2::new((index % columns) as f32, (index / columns) as f32) * cell_size;
+                Rect::from_corners(min, min + cell_size)
+            },
+            LeafAtlasLayout::Rects(ref rects) => rects.get(index).copied().unwrap_or(Rect::new(0.0, 0.0, 1.0, 1.0)),
         }
     }
 }
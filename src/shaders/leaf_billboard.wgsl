@@ -0,0 +1,163 @@
+// Camera facing leaf quads
+//
+// Every vertex carries the center of its leaf quad and its corner offset (x: right, y: up; in the local space of the mesh).
+// The quad is rebuilt around the center in the plane of the current view; its normal faces the camera (blended with the normal of the mesh).
+// Used for the main pass and the prepass (shadows/depth/normals) with the default fragment shaders of the StandardMaterial.
+
+#import bevy_pbr::{
+    mesh_functions,
+    mesh_view_bindings::view,
+    view_transformations::position_world_to_clip,
+}
+
+#ifdef PREPASS_PIPELINE
+#import bevy_pbr::prepass_io::VertexOutput
+#else
+#import bevy_pbr::forward_io::VertexOutput
+#endif
+
+// same locations as bevy_pbr::prepass_io::Vertex/bevy_pbr::forward_io::Vertex plus the billboard attributes
+struct Vertex {
+    @builtin(instance_index) instance_index: u32,
+    @location(0) position: vec3<f32>,
+#ifdef PREPASS_PIPELINE
+#ifdef VERTEX_UVS_A
+    @location(1) uv: vec2<f32>,
+#endif
+#ifdef VERTEX_UVS_B
+    @location(2) uv_b: vec2<f32>,
+#endif
+#ifdef NORMAL_PREPASS_OR_DEFERRED_PREPASS
+#ifdef VERTEX_NORMALS
+    @location(3) normal: vec3<f32>,
+#endif
+#ifdef VERTEX_TANGENTS
+    @location(4) tangent: vec4<f32>,
+#endif
+#endif
+#ifdef VERTEX_COLORS
+    @location(7) color: vec4<f32>,
+#endif
+#else // PREPASS_PIPELINE
+#ifdef VERTEX_NORMALS
+    @location(1) normal: vec3<f32>,
+#endif
+#ifdef VERTEX_UVS_A
+    @location(2) uv: vec2<f32>,
+#endif
+#ifdef VERTEX_UVS_B
+    @location(3) uv_b: vec2<f32>,
+#endif
+#ifdef VERTEX_TANGENTS
+    @location(4) tangent: vec4<f32>,
+#endif
+#ifdef VERTEX_COLORS
+    @location(5) color: vec4<f32>,
+#endif
+#endif // PREPASS_PIPELINE
+#ifdef LEAF_BILLBOARD
+    @location(14) leaf_center: vec3<f32>,
+    @location(15) leaf_corner: vec2<f32>,
+#endif
+}
+
+// see LeafBillboardExtension::normal_blend
+@group(2) @binding(100) var<uniform> normal_blend: f32;
+
+// position of the vertex in world space (rotated towards the camera if the billboard attributes are present)
+fn billboard_world_position(vertex: Vertex, world_from_local: mat4x4<f32>) -> vec4<f32> {
+#ifdef LEAF_BILLBOARD
+    let camera_right = normalize(view.world_from_view[0].xyz);
+    let camera_up = normalize(view.world_from_view[1].xyz);
+    // keep the size of the leaves in line with the (uniform) scale of the tree
+    let scale = length(world_from_local[0].xyz);
+    let world_center = mesh_functions::mesh_position_local_to_world(world_from_local, vec4<f32>(vertex.leaf_center, 1.0));
+    return vec4<f32>(world_center.xyz + (camera_right * vertex.leaf_corner.x + camera_up * vertex.leaf_corner.y) * scale, 1.0);
+#else
+    return mesh_functions::mesh_position_local_to_world(world_from_local, vec4<f32>(vertex.position, 1.0));
+#endif
+}
+
+// normal in world space (facing the camera like the rotated quad, blended with the normal of the mesh)
+fn billboard_world_normal(local_normal: vec3<f32>, instance_index: u32) -> vec3<f32> {
+    let world_normal = mesh_functions::mesh_normal_local_to_world(local_normal, instance_index);
+#ifdef LEAF_BILLBOARD
+    let camera_normal = normalize(view.world_from_view[2].xyz);
+    return normalize(mix(camera_normal, world_normal, normal_blend));
+#else
+    return world_normal;
+#endif
+}
+
+#ifdef VERTEX_TANGENTS
+// tangent along the right vector of the camera (u increases to the right on every leaf quad)
+fn billboard_world_tangent(vertex: Vertex, world_from_local: mat4x4<f32>, world_normal: vec3<f32>) -> vec4<f32> {
+#ifdef LEAF_BILLBOARD
+    let camera_right = normalize(view.world_from_view[0].xyz);
+    let tangent = camera_right - world_normal * dot(world_normal, camera_right);
+    return vec4<f32>(normalize(tangent), vertex.tangent.w);
+#else
+    return mesh_functions::mesh_tangent_local_to_world(world_from_local, vertex.tangent, vertex.instance_index);
+#endif
+}
+#endif
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+
+    let world_from_local = mesh_functions::get_world_from_local(vertex.instance_index);
+
+    out.world_position = billboard_world_position(vertex, world_from_local);
+    out.position = position_world_to_clip(out.world_position.xyz);
+
+#ifdef UNCLIPPED_DEPTH_ORTHO_EMULATION
+    out.unclipped_depth = out.position.z;
+    out.position.z = min(out.position.z, 1.0); // Clamp depth to avoid clipping
+#endif
+
+#ifdef VERTEX_UVS_A
+    out.uv = vertex.uv;
+#endif
+#ifdef VERTEX_UVS_B
+    out.uv_b = vertex.uv_b;
+#endif
+
+#ifdef PREPASS_PIPELINE
+#ifdef NORMAL_PREPASS_OR_DEFERRED_PREPASS
+#ifdef VERTEX_NORMALS
+    out.world_normal = billboard_world_normal(vertex.normal, vertex.instance_index);
+#ifdef VERTEX_TANGENTS
+    out.world_tangent = billboard_world_tangent(vertex, world_from_local, out.world_normal);
+#endif
+#endif
+#endif
+#else // PREPASS_PIPELINE
+#ifdef VERTEX_NORMALS
+    out.world_normal = billboard_world_normal(vertex.normal, vertex.instance_index);
+#ifdef VERTEX_TANGENTS
+    out.world_tangent = billboard_world_tangent(vertex, world_from_local, out.world_normal);
+#endif
+#endif
+#endif // PREPASS_PIPELINE
+
+#ifdef VERTEX_COLORS
+    out.color = vertex.color;
+#endif
+
+#ifdef MOTION_VECTOR_PREPASS
+    let previous_world_from_local = mesh_functions::get_previous_world_from_local(vertex.instance_index);
+    out.previous_world_position = billboard_world_position(vertex, previous_world_from_local);
+#endif
+
+#ifdef VERTEX_OUTPUT_INSTANCE_INDEX
+    out.instance_index = vertex.instance_index;
+#endif
+
+#ifdef VISIBILITY_RANGE_DITHER
+    out.visibility_range_dither = mesh_functions::get_visibility_range_dither_level(
+        vertex.instance_index, world_from_local[3]);
+#endif
+
+    return out;
+}
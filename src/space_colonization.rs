@@ -0,0 +1,123 @@
+use bevy::prelude::*;
+use fastrand::Rng;
+
+use crate::{errors::SpaceColonizationError, meshgen::{SkeletonNode, TreeGenerationContext}, obstacles::distance_to_obstacles, settings::SpaceColonizationParams};
+
+/// attempts to place each attraction point (samples outside of the ellipsoid or inside of obstacles are discarded)
+const MAX_SAMPLES_PER_ATTRACTION_POINT: usize = 32;
+
+/// the generation fails if the trunk needs more steps to reach the crown (protects against unreachable crowns)
+const MAX_TRUNK_STEPS: usize = 10_000;
+
+/// a point inside of the crown the branches grow towards
+struct AttractionPoint {
+    position: Vec3,
+    /// index of the closest node of the skeleton
+    closest_node: usize,
+    /// distance to the closest node
+    distance: f32,
+}
+
+/// Grow the skeleton of a tree towards attraction points inside of the crown volume (space colonization)
+///
+/// The first node is the base of the trunk; every other node has a parent with a lower index.
+/// The radii follow the pipe model (the trunk has the given base radius).
+/// Fails if the trunk needs more than MAX_TRUNK_STEPS steps to reach the crown.
+pub(crate) fn grow_skeleton(params: &SpaceColonizationParams, base_radius: f32, context: &TreeGenerationContext, rng: &mut Rng) -> Result<Vec<SkeletonNode>, SpaceColonizationError> {
+    let crown_center = Vec3::Y * params.crown_height;
+    let step = params.step_length.max(0.01);
+
+    // attraction points: evenly distributed inside of the crown ellipsoid (outside of obstacles)
+    // the attempts are limited: a crown (mostly) inside of obstacles grows towards the points found
+    let mut attraction_points: Vec<AttractionPoint> = Vec::with_capacity(params.attraction_points as usize);
+    for _ in 0..params.attraction_points as usize * MAX_SAMPLES_PER_ATTRACTION_POINT {
+        if attraction_points.len() >= params.attraction_points as usize {
+            break;
+        }
+        let sample = Vec3::new(rng.f32(), rng.f32(), rng.f32()) * 2.0 - Vec3::ONE;
+        if sample.length_squared() > 1.0 {
+            continue;
+        }
+        let position = crown_center + sample * params.crown_size;
+        if distance_to_obstacles(&context.obstacles, position) < 0.0 {
+            continue;
+        }
+        attraction_points.push(AttractionPoint { position, closest_node: 0, distance: position.length() });
+    }
+
+    // trunk: straight up until the crown is within reach (or the top of the crown is passed)
+    let mut nodes = vec![SkeletonNode { position: Vec3::ZERO, parent: None, radius: 0.0, level: 0 }];
+    let crown_top = params.crown_height + params.crown_size.y.abs();
+    loop {
+        let last = nodes.len() - 1;
+        if nodes[last].position.y > crown_top || attraction_points.iter().any(|point| point.distance < params.influence_distance) {
+            break;
+        }
+        if last >= MAX_TRUNK_STEPS {
+            return Err(SpaceColonizationError);
+        }
+        nodes.push(SkeletonNode { position: nodes[last].position + Vec3::Y * step, parent: Some(last), radius: 0.0, level: 0 });
+        update_closest_nodes(&mut attraction_points, &nodes, last + 1);
+    }
+
+    // branches: each node grows one step towards the attraction points closest to it
+    for _ in 0..params.max_iterations {
+        attraction_points.retain(|point| point.distance > params.kill_distance);
+        if attraction_points.is_empty() {
+            break;
+        }
+
+        let mut pull = vec![Vec3::ZERO; nodes.len()];
+        for point in attraction_points.iter().filter(|point| point.distance < params.influence_distance) {
+            pull[point.closest_node] += (point.position - nodes[point.closest_node].position).normalize_or_zero();
+        }
+
+        let first_new_node = nodes.len();
+        for (parent, pull) in pull.into_iter().enumerate().filter(|(_, pull)| *pull != Vec3::ZERO) {
+            let jitter = (Vec3::new(rng.f32(), rng.f32(), rng.f32()) * 2.0 - Vec3::ONE) * params.jitter;
+            let Some(direction) = (pull.normalize_or_zero() + jitter).try_normalize() else {
+                continue;
+            };
+            let position = nodes[parent].position + direction * step;
+            if distance_to_obstacles(&context.obstacles, position) < 0.0 {
+                continue;
+            }
+            nodes.push(SkeletonNode { position, parent: Some(parent), radius: 0.0, level: 0 });
+        }
+        if nodes.len() == first_new_node {
+            break;
+        }
+        update_closest_nodes(&mut attraction_points, &nodes, first_new_node);
+    }
+
+    // pipe model: radius^exponent of a node is the sum of radius^exponent of its children (all tips have the same radius)
+    let exponent = params.radius_exponent.max(1.0);
+    let mut pipes: Vec<f32> = vec![0.0; nodes.len()];
+    for index in (0..nodes.len()).rev() {
+        if pipes[index] == 0.0 {
+            pipes[index] = 1.0;
+        }
+        if let Some(parent) = nodes[index].parent {
+            pipes[parent] += pipes[index];
+        }
+    }
+    let scale = base_radius / pipes[0].powf(1.0 / exponent);
+    for (node, pipe) in nodes.iter_mut().zip(pipes) {
+        node.radius = pipe.powf(1.0 / exponent) * scale;
+    }
+
+    Ok(nodes)
+}
+
+/// update the closest node of all attraction points with the nodes starting at first_new_node
+fn update_closest_nodes(attraction_points: &mut [AttractionPoint], nodes: &[SkeletonNode], first_new_node: usize) {
+    for point in attraction_points.iter_mut() {
+        for (index, node) in nodes.iter().enumerate().skip(first_new_node) {
+            let distance = point.position.distance(node.position);
+            if distance < point.distance {
+                point.distance = distance;
+                point.closest_node = index;
+            }
+        }
+    }
+}